@@ -0,0 +1,142 @@
+// Local HTTP control API for driving a running instance, e.g. from an orchestration layer
+//
+// All requests are passed as commands into the message loop and answered with its result as
+// JSON. The API is only reachable from the local host and has no authentication.
+//
+//   GET    /peers                       list the peers and their connection states
+//   POST   /peers/:id/:action           mute|unmute|hide|show|stop|resume|renegotiate a peer
+//   DELETE /peers/:id                   hang up on a peer
+//   PUT    /bitrate                     set the encoder bitrates {"video": kbit/s, "audio": kbit/s}
+//   GET    /recording                   the file currently recorded to, or null
+//   POST   /recording                   start recording {"file": "room.webm"}
+//   DELETE /recording                   stop recording
+
+use std::path::PathBuf;
+
+use futures::channel::{mpsc, oneshot};
+
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+use tide::{Body, Request as HttpRequest, Response, StatusCode};
+
+use crate::Command;
+
+// What the message loop is asked to do
+#[derive(Debug)]
+pub enum Request {
+    ListPeers,
+    GetRecording,
+    Command(Command),
+}
+
+// A request together with the channel for its reply
+#[derive(Debug)]
+pub struct ApiRequest {
+    pub request: Request,
+    pub reply: oneshot::Sender<Result<Value, anyhow::Error>>,
+}
+
+type State = mpsc::UnboundedSender<ApiRequest>;
+
+#[derive(Debug, Deserialize)]
+struct BitrateBody {
+    video: Option<u32>,
+    audio: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordingBody {
+    file: PathBuf,
+}
+
+// Serve the control API on the given port until the application shuts down
+pub async fn serve(requests: State, port: u16) -> Result<(), anyhow::Error> {
+    let mut server = tide::with_state(requests);
+    server.at("/peers").get(list_peers);
+    server.at("/peers/:id").delete(kick_peer);
+    server.at("/peers/:id/:action").post(peer_action);
+    server.at("/bitrate").put(set_bitrate);
+    server
+        .at("/recording")
+        .get(get_recording)
+        .post(start_recording)
+        .delete(stop_recording);
+
+    println!("Serving control API at http://127.0.0.1:{}", port);
+    server.listen(format!("127.0.0.1:{}", port)).await?;
+
+    Ok(())
+}
+
+// Pass a request on to the message loop and reply with its result
+async fn send(req: &HttpRequest<State>, request: Request) -> tide::Result {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let unavailable = || tide::Error::from_str(StatusCode::ServiceUnavailable, "Shutting down");
+
+    req.state()
+        .unbounded_send(ApiRequest {
+            request,
+            reply: reply_tx,
+        })
+        .map_err(|_| unavailable())?;
+
+    match reply_rx.await.map_err(|_| unavailable())? {
+        Ok(value) => Ok(Response::builder(StatusCode::Ok)
+            .body(Body::from_json(&value)?)
+            .build()),
+        Err(err) => Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            err.to_string(),
+        )),
+    }
+}
+
+async fn list_peers(req: HttpRequest<State>) -> tide::Result {
+    send(&req, Request::ListPeers).await
+}
+
+// The actions are the same as the peer commands read from stdin
+async fn peer_action(req: HttpRequest<State>) -> tide::Result {
+    let action = req.param("action")?;
+    let command = match format!("{} {}", action, req.param("id")?).parse::<Command>() {
+        Ok(command @ Command::MuteAudio { .. })
+        | Ok(command @ Command::HideVideo { .. })
+        | Ok(command @ Command::SendMedia { .. })
+        | Ok(command @ Command::Renegotiate { .. }) => command,
+        _ => {
+            return Err(tide::Error::from_str(
+                StatusCode::NotFound,
+                format!("Unknown action {}", action),
+            ))
+        }
+    };
+
+    send(&req, Request::Command(command)).await
+}
+
+async fn kick_peer(req: HttpRequest<State>) -> tide::Result {
+    let peer_id = req.param("id")?.to_string();
+
+    send(&req, Request::Command(Command::Kick { peer_id })).await
+}
+
+async fn set_bitrate(mut req: HttpRequest<State>) -> tide::Result {
+    let BitrateBody { video, audio } = req.body_json().await?;
+
+    send(&req, Request::Command(Command::SetBitrate { video, audio })).await
+}
+
+async fn get_recording(req: HttpRequest<State>) -> tide::Result {
+    send(&req, Request::GetRecording).await
+}
+
+async fn start_recording(mut req: HttpRequest<State>) -> tide::Result {
+    let RecordingBody { file } = req.body_json().await?;
+
+    send(&req, Request::Command(Command::StartRecording { file })).await
+}
+
+async fn stop_recording(req: HttpRequest<State>) -> tide::Result {
+    send(&req, Request::Command(Command::StopRecording)).await
+}
@@ -0,0 +1,315 @@
+// Retransmission and forward error correction for recovering from packet loss
+//
+// NACK with RTX and ULPFEC/RED are enabled per transceiver of webrtcbin, which then also adds the
+// matching payload types and feedback attributes to the SDP. Opus in-band FEC and DTX are enabled
+// on the encoder and announced in the fmtp of the Opus payload type, and in-band FEC from the peer
+// is used by the decoder.
+
+use gst::prelude::*;
+
+use anyhow::anyhow;
+
+use crate::config::ResilienceConfig;
+
+// What to enable on a transceiver: NACK with RTX, and ULPFEC/RED with the given percentage of
+// overhead
+#[derive(Debug, PartialEq, Eq)]
+struct TransceiverSettings {
+    nack: bool,
+    fec_percentage: Option<u32>,
+}
+
+impl TransceiverSettings {
+    // ULPFEC/RED is only used for video
+    fn new(video: bool, config: &ResilienceConfig) -> Self {
+        TransceiverSettings {
+            nack: config.nack,
+            fec_percentage: if config.fec && video {
+                Some(config.fec_percentage)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+// Enable retransmissions and FEC on a transceiver. Older versions of webrtcbin don't have the
+// properties for this, in which case it is skipped
+pub fn configure_transceiver(
+    transceiver: &gst_webrtc::WebRTCRTPTransceiver,
+    video: bool,
+    config: &ResilienceConfig,
+) {
+    let settings = TransceiverSettings::new(video, config);
+
+    if settings.nack {
+        if transceiver.find_property("do-nack").is_some() {
+            transceiver.set_property("do-nack", &true).unwrap();
+        } else {
+            println!("webrtcbin doesn't support NACK, not enabling retransmissions");
+        }
+    }
+
+    if let Some(fec_percentage) = settings.fec_percentage {
+        if transceiver.find_property("fec-type").is_some() {
+            transceiver
+                .set_property_from_str("fec-type", "ulp-red")
+                .unwrap();
+            transceiver
+                .set_property("fec-percentage", &fec_percentage)
+                .unwrap();
+        } else {
+            println!("webrtcbin doesn't support FEC, not enabling ULPFEC/RED");
+        }
+    }
+}
+
+// Whether a sink pad of webrtcbin is fed with audio, according to the media field of the RTP caps
+// of the payloader linked to it
+pub fn is_audio_pad(pad: &gst::Pad) -> bool {
+    let caps = match pad.get_current_caps().or_else(|| pad.peer_query_caps(None)) {
+        Some(caps) => caps,
+        None => return false,
+    };
+
+    caps.get_structure(0)
+        .and_then(|s| s.get::<&str>("media").ok().flatten())
+        .map_or(false, |media| media == "audio")
+}
+
+// Enable in-band FEC and DTX on our Opus encoder
+pub fn configure_audio_encoder(encoder: &gst::Element, config: &ResilienceConfig) {
+    if config.opus_fec {
+        encoder.set_property("inband-fec", &true).unwrap();
+        encoder
+            .set_property(
+                "packet-loss-percentage",
+                &(config.expected_packet_loss as i32),
+            )
+            .unwrap();
+    }
+
+    if config.opus_dtx {
+        encoder.set_property("dtx", &true).unwrap();
+    }
+}
+
+// Let the Opus decoders created by decodebin use the in-band FEC sent by the peer
+pub fn configure_decodebin(decodebin: &gst::Element, config: &ResilienceConfig) {
+    if !config.opus_fec {
+        return;
+    }
+
+    let decodebin = decodebin.downcast_ref::<gst::Bin>().expect("not a bin");
+    decodebin.connect_element_added(|_decodebin, element| {
+        let is_opusdec = element
+            .get_factory()
+            .map_or(false, |factory| factory.get_name() == "opusdec");
+        if is_opusdec {
+            element.set_property("use-inband-fec", &true).unwrap();
+        }
+    });
+}
+
+// The fmtp parameters for announcing Opus in-band FEC and DTX, if any of them is enabled
+fn opus_parameters(config: &ResilienceConfig) -> Option<String> {
+    let mut parameters = Vec::new();
+    if config.opus_fec {
+        parameters.push("useinbandfec=1");
+    }
+    if config.opus_dtx {
+        parameters.push("usedtx=1");
+    }
+
+    if parameters.is_empty() {
+        None
+    } else {
+        Some(parameters.join(";"))
+    }
+}
+
+// Add the parameters to the fmtp of all Opus payload types of the SDP, or add an fmtp for them
+fn add_fmtp_parameters(sdp: &str, parameters: &str) -> String {
+    // Payload types of Opus and whether they already have an fmtp
+    let mut opus = Vec::new();
+    for line in sdp.lines() {
+        if line.starts_with("a=rtpmap:") && line.to_lowercase().contains(" opus/") {
+            let pt = line["a=rtpmap:".len()..].split(' ').next().unwrap();
+            let has_fmtp = sdp.lines().any(|line| {
+                line.starts_with("a=fmtp:") && line["a=fmtp:".len()..].split(' ').next() == Some(pt)
+            });
+            opus.push((pt.to_string(), has_fmtp));
+        }
+    }
+
+    let mut lines = Vec::new();
+    for line in sdp.lines() {
+        let fmtp_pt = if line.starts_with("a=fmtp:") {
+            line["a=fmtp:".len()..].split(' ').next()
+        } else {
+            None
+        };
+
+        if fmtp_pt.map_or(false, |pt| opus.iter().any(|(opus_pt, _)| opus_pt == pt)) {
+            lines.push(format!("{};{}", line, parameters));
+            continue;
+        }
+
+        lines.push(line.to_string());
+
+        if line.starts_with("a=rtpmap:") {
+            let pt = line["a=rtpmap:".len()..].split(' ').next().unwrap();
+            if opus
+                .iter()
+                .any(|(opus_pt, has_fmtp)| opus_pt == pt && !has_fmtp)
+            {
+                lines.push(format!("a=fmtp:{} {}", pt, parameters));
+            }
+        }
+    }
+    lines.push(String::new());
+
+    lines.join("\r\n")
+}
+
+// Announce Opus in-band FEC and DTX in the fmtp of the Opus payload types of our SDP
+pub fn add_opus_parameters(
+    description: gst_webrtc::WebRTCSessionDescription,
+    config: &ResilienceConfig,
+) -> Result<gst_webrtc::WebRTCSessionDescription, anyhow::Error> {
+    let parameters = match opus_parameters(config) {
+        Some(parameters) => parameters,
+        None => return Ok(description),
+    };
+
+    let sdp = description
+        .get_sdp()
+        .as_text()
+        .map_err(|_| anyhow!("Can't serialize SDP"))?;
+    let sdp = add_fmtp_parameters(&sdp, &parameters);
+
+    let sdp = gst_sdp::SDPMessage::parse_buffer(sdp.as_bytes())
+        .map_err(|_| anyhow!("Failed to parse modified SDP"))?;
+
+    Ok(gst_webrtc::WebRTCSessionDescription::new(
+        description.get_type(),
+        sdp,
+    ))
+}
+
+// Packets recovered by retransmissions and FEC so far, as reported by the elements webrtcbin
+// created for them
+pub fn recovery_stats(webrtcbin: &gst::Element) -> Option<String> {
+    let webrtcbin = webrtcbin.downcast_ref::<gst::Bin>()?;
+
+    let mut stats = Vec::new();
+    for element in webrtcbin.iterate_recurse().filter_map(Result::ok) {
+        let factory = match element.get_factory() {
+            Some(factory) => factory.get_name(),
+            None => continue,
+        };
+
+        let properties: &[&str] = match factory.as_str() {
+            "rtprtxsend" => &["num-rtx-requests", "num-rtx-packets"],
+            "rtprtxreceive" => &[
+                "num-rtx-requests",
+                "num-rtx-packets",
+                "num-rtx-assoc-packets",
+            ],
+            "rtpulpfecdec" => &["recovered", "unrecovered"],
+            _ => continue,
+        };
+
+        let values = properties
+            .iter()
+            .filter_map(|property| {
+                let value = element
+                    .get_property(property)
+                    .ok()?
+                    .get_some::<u32>()
+                    .ok()?;
+                Some(format!("{}={}", property, value))
+            })
+            .collect::<Vec<_>>();
+        stats.push(format!("{} {}", factory, values.join(" ")));
+    }
+
+    if stats.is_empty() {
+        None
+    } else {
+        Some(stats.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transceiver_settings() {
+        let mut config = ResilienceConfig::default();
+        assert_eq!(
+            TransceiverSettings::new(true, &config),
+            TransceiverSettings {
+                nack: false,
+                fec_percentage: None
+            }
+        );
+
+        config.nack = true;
+        config.fec = true;
+        config.fec_percentage = 20;
+        assert_eq!(
+            TransceiverSettings::new(true, &config),
+            TransceiverSettings {
+                nack: true,
+                fec_percentage: Some(20)
+            }
+        );
+        assert_eq!(
+            TransceiverSettings::new(false, &config),
+            TransceiverSettings {
+                nack: true,
+                fec_percentage: None
+            }
+        );
+    }
+
+    #[test]
+    fn opus_fmtp() {
+        let mut config = ResilienceConfig::default();
+        assert_eq!(opus_parameters(&config), None);
+
+        config.opus_dtx = true;
+        assert_eq!(opus_parameters(&config).unwrap(), "usedtx=1");
+
+        config.opus_fec = true;
+        assert_eq!(opus_parameters(&config).unwrap(), "useinbandfec=1;usedtx=1");
+    }
+
+    #[test]
+    fn add_opus_fmtp() {
+        // One Opus payload type without fmtp, one with, and VP8 that is left alone
+        let sdp = "v=0\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 97 98\r\n\
+            a=rtpmap:97 OPUS/48000/2\r\n\
+            a=rtpmap:98 opus/48000/2\r\n\
+            a=fmtp:98 minptime=10\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+            a=rtpmap:96 VP8/90000\r\n\
+            a=fmtp:96 max-fr=30\r\n";
+
+        assert_eq!(
+            add_fmtp_parameters(sdp, "useinbandfec=1"),
+            "v=0\r\n\
+             m=audio 9 UDP/TLS/RTP/SAVPF 97 98\r\n\
+             a=rtpmap:97 OPUS/48000/2\r\n\
+             a=fmtp:97 useinbandfec=1\r\n\
+             a=rtpmap:98 opus/48000/2\r\n\
+             a=fmtp:98 minptime=10;useinbandfec=1\r\n\
+             m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+             a=rtpmap:96 VP8/90000\r\n\
+             a=fmtp:96 max-fr=30\r\n"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+// Embedded WebRTC-HTTP Ingestion Protocol (WHIP) endpoint
+//
+// Publishers POST their offer to /whip and are added to the room as a receive-only peer. The
+// answer is returned together with the URL of the resource for the publisher, which accepts
+// trickled ICE candidates via PATCH and ends the session via DELETE.
+
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use async_std::future;
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+
+use async_tungstenite::tungstenite;
+use tungstenite::Message as WsMessage;
+
+use gst::prelude::*;
+
+use tide::{Request, Response, StatusCode};
+
+use anyhow::anyhow;
+
+use crate::{AppWeak, Direction, JsonMsg, Peer};
+
+// How long to wait for webrtcbin to create the answer and gather all candidates
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Serve the WHIP endpoint on the given port until the application shuts down
+pub async fn serve(app: AppWeak, port: u16) -> Result<(), anyhow::Error> {
+    let mut server = tide::with_state(app);
+    server.at("/whip").post(create_resource);
+    server
+        .at("/whip/:id")
+        .patch(trickle_candidates)
+        .delete(delete_resource);
+
+    println!("Accepting WHIP publishers at http://0.0.0.0:{}/whip", port);
+    server.listen(format!("0.0.0.0:{}", port)).await?;
+
+    Ok(())
+}
+
+// Get the WHIP publisher for the resource of the request
+fn get_publisher(req: &Request<AppWeak>) -> tide::Result<Peer> {
+    let app = req
+        .state()
+        .upgrade()
+        .ok_or_else(|| tide::Error::from_str(StatusCode::ServiceUnavailable, "Shutting down"))?;
+
+    let peer_id = req.param("id")?;
+
+    match app.get_peer(peer_id) {
+        Ok(peer) if peer.whip => Ok(peer),
+        _ => Err(tide::Error::from_str(
+            StatusCode::NotFound,
+            format!("No WHIP resource {}", peer_id),
+        )),
+    }
+}
+
+// Add a new publisher for the offer and reply with our answer
+async fn create_resource(mut req: Request<AppWeak>) -> tide::Result {
+    let offer = req.body_string().await?;
+
+    let app = req
+        .state()
+        .upgrade()
+        .ok_or_else(|| tide::Error::from_str(StatusCode::ServiceUnavailable, "Shutting down"))?;
+
+    // Random UUIDs don't collide with the ids of the other peers in the room
+    let peer_id = Uuid::new_v4().to_string();
+
+    println!("Adding WHIP publisher {}", peer_id);
+
+    // The publisher only sends to us, and the answer for it arrives on its own channel
+    let (msg_tx, mut msg_rx) = mpsc::unbounded::<WsMessage>();
+    let peer = app.create_peer(peer_id.clone(), false, Direction::Recv, Some(msg_tx))?;
+
+    // Offers we can't handle are the fault of the publisher
+    if let Err(err) = peer.handle_sdp("offer", &offer) {
+        let _ = app.remove_peer(&peer_id);
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            format!("Invalid offer: {}", err),
+        ));
+    }
+    drop(app);
+
+    let answer = match future::timeout(ANSWER_TIMEOUT, msg_rx.next()).await {
+        Ok(Some(WsMessage::Text(text))) => match serde_json::from_str::<JsonMsg>(&text) {
+            Ok(JsonMsg::Sdp { sdp, .. }) => Ok(sdp),
+            _ => Err(anyhow!("Unexpected message for WHIP publisher: {}", text)),
+        },
+        _ => Err(anyhow!("No answer for WHIP publisher {}", peer_id)),
+    };
+
+    let answer = match answer {
+        Ok(answer) => answer,
+        Err(err) => {
+            if let Some(app) = req.state().upgrade() {
+                let _ = app.remove_peer(&peer_id);
+            }
+            return Err(err.into());
+        }
+    };
+
+    Ok(Response::builder(StatusCode::Created)
+        .header("Content-Type", "application/sdp")
+        .header("Location", format!("/whip/{}", peer_id))
+        .body(answer)
+        .build())
+}
+
+// Pass the candidates of an SDP fragment from the publisher on to its webrtcbin
+async fn trickle_candidates(mut req: Request<AppWeak>) -> tide::Result {
+    let fragment = req.body_string().await?;
+    let peer = get_publisher(&req)?;
+
+    // Candidates belong to the media section of the last mid before them
+    let mut mid = None;
+    for line in fragment.lines() {
+        if line.starts_with("a=mid:") {
+            mid = Some(&line["a=mid:".len()..]);
+        } else if line.starts_with("a=candidate:") {
+            let mid = mid.ok_or_else(|| {
+                tide::Error::from_str(StatusCode::BadRequest, "Candidate without mid")
+            })?;
+            let sdp_mline_index = mline_index(&peer, mid).ok_or_else(|| {
+                tide::Error::from_str(StatusCode::BadRequest, format!("Unknown mid {}", mid))
+            })?;
+
+            peer.handle_ice(sdp_mline_index, &line["a=".len()..])?;
+        }
+    }
+
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+// Remove the publisher again once it is done
+async fn delete_resource(req: Request<AppWeak>) -> tide::Result {
+    let peer = get_publisher(&req)?;
+
+    if let Some(app) = req.state().upgrade() {
+        app.remove_peer(&peer.peer_id)?;
+    }
+
+    Ok(Response::new(StatusCode::Ok))
+}
+
+// Find the index of the media section with the given mid in the offer of the publisher
+fn mline_index(peer: &Peer, mid: &str) -> Option<u32> {
+    let offer = peer
+        .webrtcbin
+        .get_property("remote-description")
+        .ok()?
+        .get::<gst_webrtc::WebRTCSessionDescription>()
+        .ok()??;
+    let sdp = offer.get_sdp();
+
+    (0..sdp.medias_len()).find(|&idx| {
+        sdp.get_media(idx)
+            .and_then(|media| media.get_attribute_val("mid"))
+            == Some(mid)
+    })
+}
@@ -0,0 +1,896 @@
+mod macos_workaround;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::Poll;
+
+use structopt::StructOpt;
+
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use futures::channel::mpsc;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::sink::{Sink, SinkExt};
+use futures::stream::StreamExt;
+
+use async_tungstenite::tungstenite;
+use tungstenite::Error as WsError;
+use tungstenite::Message as WsMessage;
+
+use gst::gst_element_error;
+use gst::prelude::*;
+
+use serde_derive::{Deserialize, Serialize};
+
+use anyhow::{anyhow, bail, Context};
+
+const STUN_SERVER: &str = "stun://stun.l.google.com:19302";
+
+// Maximum size of the request headers we accept
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+// The page that is served to the browsers. It connects back to us via a WebSocket on the same
+// port and plays whatever stream it receives
+const HTML_SOURCE: &str = r#"<html>
+  <head>
+    <script type="text/javascript" src="https://webrtc.github.io/adapter/adapter-latest.js"></script>
+    <script type="text/javascript">
+      var html5VideoElement;
+      var websocketConnection;
+      var webrtcPeerConnection;
+      var webrtcConfiguration;
+      var reportError;
+
+      function onLocalDescription(desc) {
+        console.log("Local description: " + JSON.stringify(desc));
+        webrtcPeerConnection.setLocalDescription(desc).then(function() {
+          websocketConnection.send(JSON.stringify({ type: "sdp", "data": webrtcPeerConnection.localDescription }));
+        }).catch(reportError);
+      }
+
+      function onIncomingSDP(sdp) {
+        console.log("Incoming SDP: " + JSON.stringify(sdp));
+        webrtcPeerConnection.setRemoteDescription(sdp).then(function() {
+          return webrtcPeerConnection.createAnswer();
+        }).then(onLocalDescription).catch(reportError);
+      }
+
+      function onIncomingICE(ice) {
+        var candidate = new RTCIceCandidate(ice);
+        console.log("Incoming ICE: " + JSON.stringify(ice));
+        webrtcPeerConnection.addIceCandidate(candidate).catch(reportError);
+      }
+
+      function onAddRemoteStream(event) {
+        html5VideoElement.srcObject = event.streams[0];
+      }
+
+      function onIceCandidate(event) {
+        if (event.candidate == null)
+          return;
+
+        console.log("Sending ICE candidate out: " + JSON.stringify(event.candidate));
+        websocketConnection.send(JSON.stringify({ "type": "ice", "data": event.candidate }));
+      }
+
+      function onServerMessage(event) {
+        var msg;
+
+        try {
+          msg = JSON.parse(event.data);
+        } catch (e) {
+          return;
+        }
+
+        if (!webrtcPeerConnection) {
+          webrtcPeerConnection = new RTCPeerConnection(webrtcConfiguration);
+          webrtcPeerConnection.ontrack = onAddRemoteStream;
+          webrtcPeerConnection.onicecandidate = onIceCandidate;
+        }
+
+        switch (msg.type) {
+          case "sdp": onIncomingSDP(msg.data); break;
+          case "ice": onIncomingICE(msg.data); break;
+          default: break;
+        }
+      }
+
+      function playStream(videoElement, hostname, port, path, configuration, reportErrorCB) {
+        var l = window.location;
+        var wsHost = (hostname != undefined) ? hostname : l.hostname;
+        var wsPort = (port != undefined) ? port : l.port;
+        var wsPath = (path != undefined) ? path : "ws";
+        if (wsPort)
+          wsPort = ":" + wsPort;
+        var wsUrl = "ws://" + wsHost + wsPort + "/" + wsPath;
+
+        html5VideoElement = videoElement;
+        webrtcConfiguration = configuration;
+        reportError = (reportErrorCB != undefined) ? reportErrorCB : function(text) {};
+
+        websocketConnection = new WebSocket(wsUrl);
+        websocketConnection.addEventListener("message", onServerMessage);
+      }
+
+      window.onload = function() {
+        var vidstream = document.getElementById("stream");
+        var config = { 'iceServers': [{ 'urls': 'stun:stun.l.google.com:19302' }] };
+        playStream(vidstream, null, null, null, config, function (errmsg) { console.error(errmsg); });
+      };
+    </script>
+  </head>
+
+  <body>
+    <div>
+      <video id="stream" autoplay playsinline>Your browser does not support video</video>
+    </div>
+  </body>
+</html>
+"#;
+
+// upgrade weak reference or return
+#[macro_export]
+macro_rules! upgrade_weak {
+    ($x:ident, $r:expr) => {{
+        match $x.upgrade() {
+            Some(o) => o,
+            None => return $r,
+        }
+    }};
+    ($x:ident) => {
+        upgrade_weak!($x, ())
+    };
+}
+
+#[derive(Debug, StructOpt)]
+struct Args {
+    #[structopt(short, long, default_value = "57778")]
+    port: u16,
+}
+
+// JSON messages we communicate with
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "lowercase")]
+enum JsonMsg {
+    Ice {
+        candidate: String,
+        #[serde(rename = "sdpMLineIndex")]
+        sdp_mline_index: u32,
+    },
+    Sdp {
+        #[serde(rename = "type")]
+        type_: String,
+        sdp: String,
+    },
+}
+
+// Strong reference to our application state
+#[derive(Debug, Clone)]
+struct App(Arc<AppInner>);
+
+// Weak reference to our application state
+#[derive(Debug, Clone)]
+struct AppWeak(Weak<AppInner>);
+
+// Actual application state
+#[derive(Debug)]
+struct AppInner {
+    pipeline: gst::Pipeline,
+    video_tee: gst::Element,
+    next_receiver_id: AtomicU32,
+    receivers: Mutex<BTreeMap<u32, Receiver>>,
+}
+
+// Strong reference to the state of one receiver
+#[derive(Debug, Clone)]
+struct Receiver(Arc<ReceiverInner>);
+
+// Weak reference to the state of one receiver
+#[derive(Debug, Clone)]
+struct ReceiverWeak(Weak<ReceiverInner>);
+
+// Actual receiver state
+#[derive(Debug)]
+struct ReceiverInner {
+    receiver_id: u32,
+    bin: gst::Bin,
+    webrtcbin: gst::Element,
+    send_msg_tx: Mutex<mpsc::UnboundedSender<WsMessage>>,
+}
+
+// To be able to access the App's fields directly
+impl std::ops::Deref for App {
+    type Target = AppInner;
+
+    fn deref(&self) -> &AppInner {
+        &self.0
+    }
+}
+
+// To be able to access the Receiver's fields directly
+impl std::ops::Deref for Receiver {
+    type Target = ReceiverInner;
+
+    fn deref(&self) -> &ReceiverInner {
+        &self.0
+    }
+}
+
+impl AppWeak {
+    // Try upgrading a weak reference to a strong one
+    fn upgrade(&self) -> Option<App> {
+        self.0.upgrade().map(App)
+    }
+}
+
+impl ReceiverWeak {
+    // Try upgrading a weak reference to a strong one
+    fn upgrade(&self) -> Option<Receiver> {
+        self.0.upgrade().map(Receiver)
+    }
+}
+
+impl App {
+    // Downgrade the strong reference to a weak reference
+    fn downgrade(&self) -> AppWeak {
+        AppWeak(Arc::downgrade(&self.0))
+    }
+
+    fn new() -> Result<(Self, impl Stream<Item = gst::Message>), anyhow::Error> {
+        // Create the GStreamer pipeline. The encoded stream is shared by all receivers and
+        // the fakesink keeps it running while nobody is connected
+        let pipeline = gst::parse_launch(
+            "videotestsrc pattern=ball is-live=true ! video/x-raw,width=640,height=360,framerate=15/1 ! \
+             videoconvert ! queue max-size-buffers=1 ! \
+             x264enc bitrate=600 speed-preset=ultrafast tune=zerolatency key-int-max=15 ! \
+             video/x-h264,profile=constrained-baseline ! queue max-size-time=100000000 ! h264parse ! \
+             rtph264pay config-interval=-1 pt=96 ! \
+             application/x-rtp,media=video,encoding-name=H264,payload=96 ! tee name=video-tee ! \
+             queue ! fakesink sync=true",
+        )?;
+
+        // Downcast from gst::Element to gst::Pipeline
+        let pipeline = pipeline
+            .downcast::<gst::Pipeline>()
+            .expect("not a pipeline");
+
+        // Get access to the tee by name
+        let video_tee = pipeline
+            .get_by_name("video-tee")
+            .expect("can't find video-tee");
+
+        // Create a stream for handling the GStreamer message asynchronously
+        let bus = pipeline.get_bus().unwrap();
+        let send_gst_msg_rx = gst::BusStream::new(&bus);
+
+        let app = App(Arc::new(AppInner {
+            pipeline,
+            video_tee,
+            next_receiver_id: AtomicU32::new(0),
+            receivers: Mutex::new(BTreeMap::new()),
+        }));
+
+        // Asynchronously set the pipeline to Playing
+        app.pipeline.call_async(|pipeline| {
+            // If this fails, post an error on the bus so we exit
+            if pipeline.set_state(gst::State::Playing).is_err() {
+                gst_element_error!(
+                    pipeline,
+                    gst::LibraryError::Failed,
+                    ("Failed to set pipeline to Playing")
+                );
+            }
+        });
+
+        Ok((app, send_gst_msg_rx))
+    }
+
+    // Handle GStreamer messages coming from the pipeline
+    fn handle_pipeline_message(&self, message: &gst::Message) -> Result<(), anyhow::Error> {
+        use gst::message::MessageView;
+
+        match message.view() {
+            MessageView::Error(err) => bail!(
+                "Error from element {}: {} ({})",
+                err.get_src()
+                    .map(|s| String::from(s.get_path_string()))
+                    .unwrap_or_else(|| String::from("None")),
+                err.get_error(),
+                err.get_debug().unwrap_or_else(|| String::from("None")),
+            ),
+            MessageView::Warning(warning) => {
+                println!("Warning: \"{}\"", warning.get_debug().unwrap());
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    // Add a new receiver that gets our stream via its own webrtcbin. The returned stream
+    // contains the WebSocket messages that have to be sent to the receiver
+    fn add_receiver(&self) -> Result<(Receiver, impl Stream<Item = WsMessage>), anyhow::Error> {
+        let receiver_id = self.next_receiver_id.fetch_add(1, Ordering::SeqCst);
+        println!("Adding receiver {}", receiver_id);
+
+        let receiver_bin = gst::parse_bin_from_description(
+            "queue name=video-queue ! webrtcbin. \
+             webrtcbin name=webrtcbin",
+            false,
+        )?;
+
+        // Get access to the webrtcbin by name
+        let webrtcbin = receiver_bin
+            .get_by_name("webrtcbin")
+            .expect("can't find webrtcbin");
+
+        // Set some properties on webrtcbin
+        webrtcbin.set_property_from_str("stun-server", STUN_SERVER);
+        webrtcbin.set_property_from_str("bundle-policy", "max-bundle");
+
+        // We only ever send to the receivers
+        for pad in webrtcbin.get_sink_pads() {
+            let transceiver = pad.get_property("transceiver").ok().and_then(|value| {
+                value
+                    .get::<gst_webrtc::WebRTCRTPTransceiver>()
+                    .ok()
+                    .flatten()
+            });
+            if let Some(transceiver) = transceiver {
+                transceiver
+                    .set_property(
+                        "direction",
+                        &gst_webrtc::WebRTCRTPTransceiverDirection::Sendonly,
+                    )
+                    .unwrap();
+            }
+        }
+
+        // Add a ghost pad for connecting to the input
+        let video_queue = receiver_bin
+            .get_by_name("video-queue")
+            .expect("can't find video-queue");
+        let video_sink_pad = gst::GhostPad::new(
+            Some("video_sink"),
+            &video_queue.get_static_pad("sink").unwrap(),
+        )
+        .unwrap();
+        receiver_bin.add_pad(&video_sink_pad).unwrap();
+
+        // Channel for outgoing WebSocket messages from other threads
+        let (send_ws_msg_tx, send_ws_msg_rx) = mpsc::unbounded::<WsMessage>();
+
+        let receiver = Receiver(Arc::new(ReceiverInner {
+            receiver_id,
+            bin: receiver_bin,
+            webrtcbin,
+            send_msg_tx: Mutex::new(send_ws_msg_tx),
+        }));
+
+        // Insert the receiver into our map
+        self.receivers
+            .lock()
+            .unwrap()
+            .insert(receiver_id, receiver.clone());
+
+        // Add to the whole pipeline
+        self.pipeline.add(&receiver.bin).unwrap();
+
+        // Connect to on-negotiation-needed to handle sending an Offer
+        let receiver_clone = receiver.downgrade();
+        receiver
+            .webrtcbin
+            .connect("on-negotiation-needed", false, move |values| {
+                let _webrtc = values[0].get::<gst::Element>().unwrap();
+
+                let receiver = upgrade_weak!(receiver_clone, None);
+                if let Err(err) = receiver.on_negotiation_needed() {
+                    gst_element_error!(
+                        receiver.bin,
+                        gst::LibraryError::Failed,
+                        ("Failed to negotiate: {:?}", err)
+                    );
+                }
+
+                None
+            })
+            .unwrap();
+
+        // Whenever there is a new ICE candidate, send it to the receiver
+        let receiver_clone = receiver.downgrade();
+        receiver
+            .webrtcbin
+            .connect("on-ice-candidate", false, move |values| {
+                let _webrtc = values[0].get::<gst::Element>().expect("Invalid argument");
+                let mlineindex = values[1].get_some::<u32>().expect("Invalid argument");
+                let candidate = values[2]
+                    .get::<String>()
+                    .expect("Invalid argument")
+                    .unwrap();
+
+                let receiver = upgrade_weak!(receiver_clone, None);
+
+                if let Err(err) = receiver.on_ice_candidate(mlineindex, candidate) {
+                    gst_element_error!(
+                        receiver.bin,
+                        gst::LibraryError::Failed,
+                        ("Failed to send ICE candidate: {:?}", err)
+                    );
+                }
+
+                None
+            })
+            .unwrap();
+
+        // Add a pad probe to the tee for blocking it and
+        // then unblock it once we reached the Playing state.
+        //
+        // Then link it and unblock, in case it got blocked
+        // in the meantime.
+        //
+        // Otherwise it might happen that data is received before
+        // the elements are ready and then an error happens.
+        let video_src_pad = self.video_tee.get_request_pad("src_%u").unwrap();
+        let video_block = video_src_pad
+            .add_probe(gst::PadProbeType::BLOCK_DOWNSTREAM, |_pad, _info| {
+                gst::PadProbeReturn::Ok
+            })
+            .unwrap();
+        video_src_pad.link(&video_sink_pad)?;
+
+        // Asynchronously set the receiver bin to Playing
+        receiver.bin.call_async(move |bin| {
+            // If this fails, post an error on the bus so we exit
+            if bin.sync_state_with_parent().is_err() {
+                gst_element_error!(
+                    bin,
+                    gst::LibraryError::Failed,
+                    ("Failed to set receiver bin to Playing")
+                );
+            }
+
+            // And now unblock
+            video_src_pad.remove_probe(video_block);
+        });
+
+        Ok((receiver, send_ws_msg_rx))
+    }
+
+    // Remove this receiver
+    fn remove_receiver(&self, receiver_id: u32) {
+        println!("Removing receiver {}", receiver_id);
+
+        let mut receivers = self.receivers.lock().unwrap();
+        if let Some(receiver) = receivers.remove(&receiver_id) {
+            drop(receivers);
+
+            // Now asynchronously remove the receiver from the pipeline
+            let app_clone = self.downgrade();
+            self.pipeline.call_async(move |_pipeline| {
+                let app = upgrade_weak!(app_clone);
+
+                // Block the tee shortly for removal
+                let video_tee_sinkpad = app.video_tee.get_static_pad("sink").unwrap();
+                let video_block = video_tee_sinkpad
+                    .add_probe(gst::PadProbeType::BLOCK_DOWNSTREAM, |_pad, _info| {
+                        gst::PadProbeReturn::Ok
+                    })
+                    .unwrap();
+
+                // Release the tee pad and unblock
+                let video_sinkpad = receiver.bin.get_static_pad("video_sink").unwrap();
+
+                if let Some(video_tee_srcpad) = video_sinkpad.get_peer() {
+                    let _ = video_tee_srcpad.unlink(&video_sinkpad);
+                    app.video_tee.release_request_pad(&video_tee_srcpad);
+                }
+                video_tee_sinkpad.remove_probe(video_block);
+
+                // Then remove the receiver bin gracefully from the pipeline
+                let _ = app.pipeline.remove(&receiver.bin);
+                let _ = receiver.bin.set_state(gst::State::Null);
+
+                println!("Removed receiver {}", receiver.receiver_id);
+            });
+        }
+    }
+}
+
+// Make sure to shut down the pipeline when it goes out of scope
+// to release any system resources
+impl Drop for AppInner {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+impl Receiver {
+    // Downgrade the strong reference to a weak reference
+    fn downgrade(&self) -> ReceiverWeak {
+        ReceiverWeak(Arc::downgrade(&self.0))
+    }
+
+    // Handle WebSocket messages from the receiver
+    fn handle_websocket_message(&self, msg: &str) -> Result<(), anyhow::Error> {
+        let json_msg: JsonMsg = serde_json::from_str(msg)?;
+
+        match json_msg {
+            JsonMsg::Sdp { type_, sdp } => self.handle_sdp(&type_, &sdp),
+            JsonMsg::Ice {
+                sdp_mline_index,
+                candidate,
+            } => self.handle_ice(sdp_mline_index, &candidate),
+        }
+    }
+
+    // Whenever webrtcbin tells us that (re-)negotiation is needed, simply ask
+    // for a new offer SDP from webrtcbin without any customization and then
+    // asynchronously send it to the receiver via the WebSocket connection
+    fn on_negotiation_needed(&self) -> Result<(), anyhow::Error> {
+        println!("starting negotiation with receiver {}", self.receiver_id);
+
+        let receiver_clone = self.downgrade();
+        let promise = gst::Promise::new_with_change_func(move |reply| {
+            let receiver = upgrade_weak!(receiver_clone);
+
+            if let Err(err) = receiver.on_offer_created(reply) {
+                gst_element_error!(
+                    receiver.bin,
+                    gst::LibraryError::Failed,
+                    ("Failed to send SDP offer: {:?}", err)
+                );
+            }
+        });
+
+        self.webrtcbin
+            .emit("create-offer", &[&None::<gst::Structure>, &promise])
+            .unwrap();
+
+        Ok(())
+    }
+
+    // Once webrtcbin has create the offer SDP for us, handle it by sending it to the receiver
+    // via the WebSocket connection
+    fn on_offer_created(
+        &self,
+        reply: Result<&gst::StructureRef, gst::PromiseError>,
+    ) -> Result<(), anyhow::Error> {
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(err) => {
+                bail!("Offer creation future got no reponse: {:?}", err);
+            }
+        };
+
+        let offer = reply
+            .get_value("offer")
+            .unwrap()
+            .get::<gst_webrtc::WebRTCSessionDescription>()
+            .expect("Invalid argument")
+            .unwrap();
+        self.webrtcbin
+            .emit("set-local-description", &[&offer, &None::<gst::Promise>])
+            .unwrap();
+
+        println!(
+            "sending SDP offer to receiver: {}",
+            offer.get_sdp().as_text().unwrap()
+        );
+
+        let message = serde_json::to_string(&JsonMsg::Sdp {
+            type_: "offer".to_string(),
+            sdp: offer.get_sdp().as_text().unwrap(),
+        })
+        .unwrap();
+
+        self.send_msg_tx
+            .lock()
+            .unwrap()
+            .unbounded_send(WsMessage::Text(message))
+            .with_context(|| format!("Failed to send SDP offer"))?;
+
+        Ok(())
+    }
+
+    // Handle incoming SDP answers from the receiver
+    fn handle_sdp(&self, type_: &str, sdp: &str) -> Result<(), anyhow::Error> {
+        if type_ == "answer" {
+            print!("Received answer:\n{}\n", sdp);
+
+            let ret = gst_sdp::SDPMessage::parse_buffer(sdp.as_bytes())
+                .map_err(|_| anyhow!("Failed to parse SDP answer"))?;
+            let answer =
+                gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Answer, ret);
+
+            self.webrtcbin
+                .emit("set-remote-description", &[&answer, &None::<gst::Promise>])
+                .unwrap();
+
+            Ok(())
+        } else {
+            bail!("Sdp type is not \"answer\" but \"{}\"", type_)
+        }
+    }
+
+    // Handle incoming ICE candidates from the receiver by passing them to webrtcbin
+    fn handle_ice(&self, sdp_mline_index: u32, candidate: &str) -> Result<(), anyhow::Error> {
+        self.webrtcbin
+            .emit("add-ice-candidate", &[&sdp_mline_index, &candidate])
+            .unwrap();
+
+        Ok(())
+    }
+
+    // Asynchronously send ICE candidates to the receiver via the WebSocket connection as a JSON
+    // message
+    fn on_ice_candidate(&self, mlineindex: u32, candidate: String) -> Result<(), anyhow::Error> {
+        let message = serde_json::to_string(&JsonMsg::Ice {
+            candidate,
+            sdp_mline_index: mlineindex,
+        })
+        .unwrap();
+
+        self.send_msg_tx
+            .lock()
+            .unwrap()
+            .unbounded_send(WsMessage::Text(message))
+            .with_context(|| format!("Failed to send ICE candidate"))?;
+
+        Ok(())
+    }
+}
+
+// At least shut down the bin here if it didn't happen so far
+impl Drop for ReceiverInner {
+    fn drop(&mut self) {
+        let _ = self.bin.set_state(gst::State::Null);
+    }
+}
+
+// Handle a new TCP connection by either serving the HTML page or, for WebSocket upgrade
+// requests, by streaming to a new receiver
+async fn handle_connection(app: App, mut stream: TcpStream) -> Result<(), anyhow::Error> {
+    // Read the complete request headers to find out what kind of request this is
+    let head = read_request_head(&mut stream).await?;
+    let request = String::from_utf8_lossy(&head).to_lowercase();
+
+    if request.contains("upgrade: websocket") {
+        // The WebSocket handshake needs to see the request headers again
+        let stream = PrefixedStream {
+            prefix: head,
+            pos: 0,
+            stream,
+        };
+        let ws = async_tungstenite::accept_async(stream).await?;
+        let (receiver, send_ws_msg_rx) = app.add_receiver()?;
+        let receiver_id = receiver.receiver_id;
+
+        let res = run_receiver(receiver, send_ws_msg_rx, ws).await;
+        app.remove_receiver(receiver_id);
+
+        res
+    } else {
+        let path = request.split_whitespace().nth(1).unwrap_or("/");
+        let response = if path == "/" || path == "/index.html" {
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/html\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\
+                 \r\n\
+                 {}",
+                HTML_SOURCE.len(),
+                HTML_SOURCE
+            )
+        } else {
+            String::from(
+                "HTTP/1.1 404 Not Found\r\n\
+                 Content-Length: 0\r\n\
+                 Connection: close\r\n\
+                 \r\n",
+            )
+        };
+
+        stream.write_all(response.as_bytes()).await?;
+
+        Ok(())
+    }
+}
+
+// Read from the stream until the end of the request headers
+async fn read_request_head(stream: &mut TcpStream) -> Result<Vec<u8>, anyhow::Error> {
+    let mut head = Vec::new();
+    let mut buf = [0; 4096];
+
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            bail!("Request headers too long");
+        }
+
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            bail!("Connection closed before end of request headers");
+        }
+        head.extend_from_slice(&buf[..len]);
+    }
+
+    Ok(head)
+}
+
+// Stream that first returns the already read request headers again before continuing with the
+// actual TCP stream
+struct PrefixedStream {
+    prefix: Vec<u8>,
+    pos: usize,
+    stream: TcpStream,
+}
+
+impl AsyncRead for PrefixedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.pos < this.prefix.len() {
+            let len = buf.len().min(this.prefix.len() - this.pos);
+            buf[..len].copy_from_slice(&this.prefix[this.pos..this.pos + len]);
+            this.pos += len;
+            return Poll::Ready(Ok(len));
+        }
+
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PrefixedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_close(cx)
+    }
+}
+
+// Message loop for one receiver until its WebSocket connection is closed
+async fn run_receiver(
+    receiver: Receiver,
+    send_ws_msg_rx: impl Stream<Item = WsMessage>,
+    ws: impl Sink<WsMessage, Error = WsError> + Stream<Item = Result<WsMessage, WsError>>,
+) -> Result<(), anyhow::Error> {
+    // Split the websocket into the Sink and Stream
+    let (mut ws_sink, ws_stream) = ws.split();
+    // Fuse the Stream, required for the select macro
+    let mut ws_stream = ws_stream.fuse();
+
+    let mut send_ws_msg_rx = send_ws_msg_rx.fuse();
+
+    loop {
+        let ws_msg = futures::select! {
+            // Handle the WebSocket messages here
+            ws_msg = ws_stream.select_next_some() => {
+                match ws_msg? {
+                    WsMessage::Close(_) => {
+                        println!("receiver {} disconnected", receiver.receiver_id);
+                        break
+                    },
+                    WsMessage::Ping(data) => Some(WsMessage::Pong(data)),
+                    WsMessage::Pong(_) => None,
+                    WsMessage::Binary(_) => None,
+                    WsMessage::Text(text) => {
+                        if let Err(err) = receiver.handle_websocket_message(&text) {
+                            println!("Failed to parse message: {}", err);
+                        }
+                        None
+                    },
+                }
+            },
+            // Handle WebSocket messages we created asynchronously
+            // to send them out now
+            ws_msg = send_ws_msg_rx.select_next_some() => Some(ws_msg),
+            // Once we're done, break the loop and return
+            complete => break,
+        };
+
+        // If there's a message to send out, do so now
+        if let Some(ws_msg) = ws_msg {
+            ws_sink.send(ws_msg).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run(args: Args) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(("0.0.0.0", args.port)).await?;
+    println!("Listening on http://0.0.0.0:{}", args.port);
+
+    // Create our application state
+    let (app, send_gst_msg_rx) = App::new()?;
+
+    let mut send_gst_msg_rx = send_gst_msg_rx.fuse();
+    let mut incoming = listener.incoming().fuse();
+
+    // And now let's start our message loop
+    loop {
+        futures::select! {
+            // Pass the GStreamer messages to the application control logic
+            gst_msg = send_gst_msg_rx.select_next_some() => {
+                app.handle_pipeline_message(&gst_msg)?;
+            },
+            // Handle each new connection in its own task
+            stream = incoming.select_next_some() => {
+                let stream = stream?;
+                let app = app.clone();
+                task::spawn(async move {
+                    if let Err(err) = handle_connection(app, stream).await {
+                        println!("Connection failed: {}", err);
+                    }
+                });
+            },
+            // Once we're done, break the loop and return
+            complete => break,
+        };
+    }
+
+    Ok(())
+}
+
+// Check if all GStreamer plugins we require are available
+fn check_plugins() -> Result<(), anyhow::Error> {
+    let needed = [
+        "videotestsrc",
+        "videoconvert",
+        "x264",
+        "videoparsersbad",
+        "webrtc",
+        "nice",
+        "dtls",
+        "srtp",
+        "rtpmanager",
+        "rtp",
+    ];
+
+    let registry = gst::Registry::get();
+    let missing = needed
+        .iter()
+        .filter(|n| registry.find_plugin(n).is_none())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        bail!("Missing plugins: {:?}", missing);
+    } else {
+        Ok(())
+    }
+}
+
+async fn async_main() -> Result<(), anyhow::Error> {
+    // Initialize GStreamer first
+    gst::init()?;
+
+    check_plugins()?;
+
+    let args = Args::from_args();
+
+    // All good, let's wait for receivers
+    run(args).await
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    macos_workaround::run(|| task::block_on(async_main()))
+}
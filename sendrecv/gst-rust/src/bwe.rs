@@ -0,0 +1,169 @@
+// Bandwidth estimation for adapting the bitrate of our video to the network
+//
+// This is a loss-based estimator similar to the one of Google Congestion Control. It uses the
+// fraction of lost packets and the round-trip time from the RTCP receiver reports of the peer,
+// as reported in the remote-inbound-rtp statistics of webrtcbin.
+
+use crate::config::BandwidthConfig;
+
+// Above this fraction of lost packets the bitrate is decreased, below the other one increased
+const HIGH_LOSS: f64 = 0.10;
+const LOW_LOSS: f64 = 0.02;
+
+// Factor for increasing the bitrate per update while there is little loss
+const INCREASE_FACTOR: f64 = 1.08;
+
+// Round-trip times this much above the lowest one seen so far mean that queues are building up
+// somewhere on the path, in seconds
+const QUEUEING_DELAY: f64 = 0.1;
+const QUEUEING_DECREASE_FACTOR: f64 = 0.85;
+
+#[derive(Debug)]
+pub struct BandwidthEstimator {
+    // In kbit/s
+    bitrate: u32,
+    min_bitrate: u32,
+    max_bitrate: u32,
+    // Lowest round-trip time seen so far, in seconds
+    min_rtt: Option<f64>,
+}
+
+impl BandwidthEstimator {
+    pub fn new(config: &BandwidthConfig, start_bitrate: u32) -> Self {
+        BandwidthEstimator {
+            bitrate: start_bitrate
+                .max(config.min_bitrate)
+                .min(config.max_bitrate),
+            min_bitrate: config.min_bitrate,
+            max_bitrate: config.max_bitrate,
+            min_rtt: None,
+        }
+    }
+
+    // The current estimate in kbit/s
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate
+    }
+
+    // Update the estimate from a new receiver report and return it
+    pub fn update(&mut self, fraction_lost: f64, rtt: Option<f64>) -> u32 {
+        let queueing = match rtt {
+            Some(rtt) => {
+                let min_rtt = self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt));
+                self.min_rtt = Some(min_rtt);
+                rtt - min_rtt > QUEUEING_DELAY
+            }
+            None => false,
+        };
+
+        let mut bitrate = f64::from(self.bitrate);
+        if fraction_lost > HIGH_LOSS {
+            bitrate *= 1.0 - 0.5 * fraction_lost;
+        } else if queueing {
+            bitrate *= QUEUEING_DECREASE_FACTOR;
+        } else if fraction_lost < LOW_LOSS {
+            bitrate *= INCREASE_FACTOR;
+        }
+
+        self.bitrate = (bitrate.round() as u32)
+            .max(self.min_bitrate)
+            .min(self.max_bitrate);
+
+        self.bitrate
+    }
+}
+
+// Loss and round-trip time from the statistics of webrtcbin, using the worst of all streams as
+// they all share the same path
+pub fn parse_stats(stats: &gst::StructureRef) -> Option<(f64, Option<f64>)> {
+    let mut result = None;
+
+    for (_, value) in stats.iter() {
+        let stream = match value.get::<gst::Structure>() {
+            Ok(Some(stream)) if stream.get_name().starts_with("RTCRemoteInboundRTP") => stream,
+            _ => continue,
+        };
+
+        let fraction_lost = match stream.get_some::<f64>("fraction-lost") {
+            Ok(fraction_lost) => fraction_lost,
+            Err(_) => continue,
+        };
+        let rtt = stream.get_some::<f64>("round-trip-time").ok();
+
+        let (worst_loss, worst_rtt) = result.unwrap_or((0.0, None));
+        result = Some((
+            fraction_lost.max(worst_loss),
+            match (rtt, worst_rtt) {
+                (Some(rtt), Some(worst_rtt)) => Some(rtt.max(worst_rtt)),
+                (rtt, worst_rtt) => rtt.or(worst_rtt),
+            },
+        ));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimator(start_bitrate: u32) -> BandwidthEstimator {
+        let config = BandwidthConfig {
+            min_bitrate: 100,
+            max_bitrate: 1000,
+            ..Default::default()
+        };
+
+        BandwidthEstimator::new(&config, start_bitrate)
+    }
+
+    #[test]
+    fn clamp_start_bitrate() {
+        assert_eq!(estimator(50).bitrate(), 100);
+        assert_eq!(estimator(500).bitrate(), 500);
+        assert_eq!(estimator(5000).bitrate(), 1000);
+    }
+
+    #[test]
+    fn loss() {
+        let mut bwe = estimator(500);
+
+        // Little loss increases the bitrate step by step up to the maximum
+        assert_eq!(bwe.update(0.0, None), 540);
+        assert_eq!(bwe.update(0.01, None), 583);
+        for _ in 0..10 {
+            bwe.update(0.0, None);
+        }
+        assert_eq!(bwe.bitrate(), 1000);
+
+        // Some loss keeps it
+        assert_eq!(bwe.update(0.05, None), 1000);
+
+        // High loss decreases it by half the loss down to the minimum
+        assert_eq!(bwe.update(0.2, None), 900);
+        assert_eq!(bwe.update(0.5, None), 675);
+        for _ in 0..10 {
+            bwe.update(1.0, None);
+        }
+        assert_eq!(bwe.bitrate(), 100);
+    }
+
+    #[test]
+    fn queueing() {
+        let mut bwe = estimator(500);
+
+        // Round-trip times close to the lowest one so far don't count as queueing
+        assert_eq!(bwe.update(0.0, Some(0.05)), 540);
+        assert_eq!(bwe.update(0.0, Some(0.12)), 583);
+
+        // Higher ones decrease the bitrate
+        assert_eq!(bwe.update(0.0, Some(0.2)), 496);
+
+        // Relative to the new lowest round-trip time
+        assert_eq!(bwe.update(0.0, Some(0.02)), 536);
+        assert_eq!(bwe.update(0.0, Some(0.15)), 456);
+
+        // High loss is handled the same way with queueing
+        assert_eq!(bwe.update(0.2, Some(0.15)), 410);
+    }
+}
@@ -0,0 +1,398 @@
+// Simulated network impairment between webrtcbin and the network
+//
+// The packets are impaired on the ICE transport of webrtcbin, i.e. right before nicesink sends them
+// and right after nicesrc received them. Only the (S)RTP and (S)RTCP packets are impaired, the ICE
+// and DTLS packets for connecting are always passed through. Which packets are dropped, duplicated
+// and how much they are delayed is decided by a random number generator with a fixed seed, so runs
+// with the same packets are reproducible.
+//
+// Packets that are delayed are taken out of the stream and passed on again by a thread per pad
+// once they are due, so that the transport itself is never blocked.
+
+use std::cell::Cell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gst::prelude::*;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::config::{Impairment, ImpairmentConfig};
+
+// Packets that would have to wait longer than this for the bandwidth cap are dropped
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(500);
+
+// Decides what happens to each packet of one direction
+#[derive(Debug)]
+struct Scheduler {
+    impairment: Impairment,
+    rng: StdRng,
+    // When the previous packet is completely sent with the bandwidth cap
+    link_free: Duration,
+    // When the previous packet is released, later packets are never released before it so that
+    // the jitter doesn't reorder them
+    last_release: Duration,
+}
+
+impl Scheduler {
+    fn new(impairment: Impairment, seed: u64) -> Self {
+        Scheduler {
+            impairment,
+            rng: StdRng::seed_from_u64(seed),
+            link_free: Duration::from_millis(0),
+            last_release: Duration::from_millis(0),
+        }
+    }
+
+    // When to release the copies of a packet of the given size in bytes arriving now, relative to
+    // the same start. Empty if the packet is dropped
+    fn schedule(&mut self, now: Duration, size: usize) -> Vec<Duration> {
+        // Always draw all random numbers so that the impairment of a packet doesn't depend on what
+        // happened to the previous ones
+        let lost = self.rng.gen_bool(self.impairment.loss / 100.0);
+        let duplicated = self.rng.gen_bool(self.impairment.duplicate / 100.0);
+        let jitter = self.rng.gen_range(0, self.impairment.jitter * 1000 + 1);
+        if lost {
+            return Vec::new();
+        }
+
+        let mut sent = now;
+        if self.impairment.bandwidth > 0 {
+            let start = cmp::max(now, self.link_free);
+            if start - now > MAX_QUEUE_DELAY {
+                return Vec::new();
+            }
+
+            // Kbit/s are bits per millisecond
+            let duration = size as u64 * 8 * 1000 / self.impairment.bandwidth;
+            self.link_free = start + Duration::from_micros(duration);
+            sent = self.link_free;
+        }
+
+        let release =
+            sent + Duration::from_millis(self.impairment.delay) + Duration::from_micros(jitter);
+        let release = cmp::max(release, self.last_release);
+        self.last_release = release;
+
+        if duplicated {
+            vec![release, release]
+        } else {
+            vec![release]
+        }
+    }
+}
+
+// Packets waiting to be passed on by the thread of a pad, in the order of their release
+#[derive(Debug, Default)]
+struct Queue {
+    packets: Mutex<VecDeque<(Instant, gst::Buffer)>>,
+    cond: Condvar,
+}
+
+thread_local! {
+    // Set for the threads passing on the delayed packets, which are not impaired again
+    static RELEASING: Cell<bool> = Cell::new(false);
+}
+
+// Impair the packets of a session bin with the given peer as configured. Has to be called before
+// webrtcbin creates its transports
+pub fn setup(bin: &gst::Bin, config: &ImpairmentConfig, peer_id: &str) {
+    let send = config.send(peer_id).clone();
+    let receive = config.receive(peer_id).clone();
+    if !send.is_active() && !receive.is_active() {
+        return;
+    }
+
+    // Both directions get their own scheduler so that the impairment of one doesn't depend on the
+    // number of packets in the other one
+    let start = Instant::now();
+    let send_scheduler = Arc::new(Mutex::new(Scheduler::new(send.clone(), config.seed)));
+    let receive_scheduler = Arc::new(Mutex::new(Scheduler::new(
+        receive.clone(),
+        config.seed.wrapping_add(1),
+    )));
+
+    // The transports are created inside bins that are only added to webrtcbin afterwards
+    let probed = Arc::new(Mutex::new(Vec::<gst::Element>::new()));
+    bin.connect_deep_element_added(move |_bin, _sub_bin, element| {
+        let mut elements = vec![element.clone()];
+        if let Some(element_bin) = element.downcast_ref::<gst::Bin>() {
+            elements.extend(element_bin.iterate_recurse().filter_map(Result::ok));
+        }
+
+        for element in elements {
+            let (pad_name, impairment, scheduler) = match element.get_factory() {
+                Some(factory) if factory.get_name() == "nicesink" => {
+                    ("sink", &send, &send_scheduler)
+                }
+                Some(factory) if factory.get_name() == "nicesrc" => {
+                    ("src", &receive, &receive_scheduler)
+                }
+                _ => continue,
+            };
+
+            let mut probed = probed.lock().unwrap();
+            if !impairment.is_active() || probed.contains(&element) {
+                continue;
+            }
+            probed.push(element.clone());
+
+            let pad = element.get_static_pad(pad_name).unwrap();
+            let queue = if impairment.is_delaying() {
+                Some(spawn_release(&pad))
+            } else {
+                None
+            };
+            add_probe(&pad, start, scheduler.clone(), queue);
+        }
+    });
+}
+
+// Whether a packet is (S)RTP or (S)RTCP according to its first byte, see RFC 7983
+fn is_rtp(buffer: &gst::BufferRef) -> bool {
+    let mut first = [0u8];
+    buffer.copy_to_slice(0, &mut first).is_ok() && (128..=191).contains(&first[0])
+}
+
+// Pass on the queued packets of a pad once they are due, until the pad is gone
+fn spawn_release(pad: &gst::Pad) -> Arc<Queue> {
+    let queue = Arc::new(Queue::default());
+    let queue_clone = queue.clone();
+    let pad_weak = pad.downgrade();
+    thread::spawn(move || {
+        RELEASING.with(|releasing| releasing.set(true));
+
+        let mut packets = queue_clone.packets.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let timeout = match packets.front() {
+                Some((release, _buffer)) if *release <= now => {
+                    let (_release, buffer) = packets.pop_front().unwrap();
+                    drop(packets);
+
+                    let pad = match pad_weak.upgrade() {
+                        Some(pad) => pad,
+                        None => return,
+                    };
+                    // Errors are seen by the streaming thread of the pad as well
+                    let _ = if pad.get_direction() == gst::PadDirection::Sink {
+                        pad.chain(buffer)
+                    } else {
+                        pad.push(buffer)
+                    };
+
+                    packets = queue_clone.packets.lock().unwrap();
+                    continue;
+                }
+                Some((release, _buffer)) => *release - now,
+                None => {
+                    if pad_weak.upgrade().is_none() {
+                        return;
+                    }
+                    Duration::from_secs(1)
+                }
+            };
+
+            packets = queue_clone.cond.wait_timeout(packets, timeout).unwrap().0;
+        }
+    });
+
+    queue
+}
+
+fn add_probe(
+    pad: &gst::Pad,
+    start: Instant,
+    scheduler: Arc<Mutex<Scheduler>>,
+    queue: Option<Arc<Queue>>,
+) {
+    // Whether to pass on a packet right away, otherwise it is dropped or queued
+    let impair = move |buffer: &gst::BufferRef| {
+        if !is_rtp(buffer) || RELEASING.with(Cell::get) {
+            return true;
+        }
+
+        let releases = scheduler
+            .lock()
+            .unwrap()
+            .schedule(start.elapsed(), buffer.get_size());
+        match queue {
+            None => !releases.is_empty(),
+            Some(ref queue) => {
+                let mut packets = queue.packets.lock().unwrap();
+                for release in releases {
+                    packets.push_back((start + release, buffer.to_owned()));
+                }
+                queue.cond.notify_one();
+
+                false
+            }
+        }
+    };
+
+    pad.add_probe(
+        gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+        move |_pad, info| match info.data {
+            Some(gst::PadProbeData::Buffer(ref buffer)) => {
+                if impair(buffer) {
+                    gst::PadProbeReturn::Ok
+                } else {
+                    gst::PadProbeReturn::Drop
+                }
+            }
+            Some(gst::PadProbeData::BufferList(ref mut list)) => {
+                // Decide in the order of the packets, but remove from the back
+                let removed = list
+                    .iter()
+                    .enumerate()
+                    .filter(|(_idx, buffer)| !impair(buffer))
+                    .map(|(idx, _buffer)| idx as u32)
+                    .collect::<Vec<_>>();
+                if removed.is_empty() {
+                    return gst::PadProbeReturn::Ok;
+                }
+
+                let list = list.make_mut();
+                for idx in removed.into_iter().rev() {
+                    list.remove(idx, 1);
+                }
+
+                if list.is_empty() {
+                    gst::PadProbeReturn::Drop
+                } else {
+                    gst::PadProbeReturn::Ok
+                }
+            }
+            _ => gst::PadProbeReturn::Ok,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn schedule_all(scheduler: &mut Scheduler, packets: u64) -> Vec<Vec<Duration>> {
+        (0..packets)
+            .map(|idx| scheduler.schedule(ms(idx * 20), 1000))
+            .collect()
+    }
+
+    #[test]
+    fn no_impairment() {
+        let mut scheduler = Scheduler::new(Impairment::default(), 0);
+        for (idx, releases) in schedule_all(&mut scheduler, 100).into_iter().enumerate() {
+            assert_eq!(releases, vec![ms(idx as u64 * 20)]);
+        }
+    }
+
+    #[test]
+    fn loss() {
+        let impairment = Impairment {
+            loss: 10.0,
+            ..Default::default()
+        };
+        let releases = schedule_all(&mut Scheduler::new(impairment.clone(), 0), 10000);
+        let lost = releases
+            .iter()
+            .filter(|releases| releases.is_empty())
+            .count();
+        assert!((900..1100).contains(&lost), "{} lost", lost);
+
+        // The same seed loses the same packets, another one different ones
+        assert_eq!(
+            schedule_all(&mut Scheduler::new(impairment.clone(), 0), 10000),
+            releases
+        );
+        assert_ne!(
+            schedule_all(&mut Scheduler::new(impairment, 1), 10000),
+            releases
+        );
+    }
+
+    #[test]
+    fn duplicate() {
+        let impairment = Impairment {
+            duplicate: 100.0,
+            ..Default::default()
+        };
+        let mut scheduler = Scheduler::new(impairment, 0);
+        assert_eq!(scheduler.schedule(ms(20), 1000), vec![ms(20), ms(20)]);
+    }
+
+    #[test]
+    fn delay_and_jitter() {
+        let impairment = Impairment {
+            delay: 100,
+            jitter: 30,
+            ..Default::default()
+        };
+        let mut scheduler = Scheduler::new(impairment, 0);
+
+        let mut last = ms(0);
+        for (idx, releases) in schedule_all(&mut scheduler, 1000).into_iter().enumerate() {
+            assert_eq!(releases.len(), 1);
+            let release = releases[0];
+            let now = ms(idx as u64 * 20);
+            assert!(release >= now + ms(100) && release <= now + ms(130));
+
+            // Not reordered by the jitter
+            assert!(release >= last);
+            last = release;
+        }
+    }
+
+    #[test]
+    fn bandwidth() {
+        // 1000 bytes take 10ms at 800 kbit/s
+        let impairment = Impairment {
+            bandwidth: 800,
+            ..Default::default()
+        };
+        let mut scheduler = Scheduler::new(impairment, 0);
+
+        // Below the cap packets are only delayed by the time for sending them
+        assert_eq!(scheduler.schedule(ms(0), 1000), vec![ms(10)]);
+        assert_eq!(scheduler.schedule(ms(20), 1000), vec![ms(30)]);
+
+        // A burst queues up behind each other
+        let burst = (0..10)
+            .map(|_idx| scheduler.schedule(ms(40), 1000))
+            .collect::<Vec<_>>();
+        let expected = (1..=10)
+            .map(|idx| vec![ms(40 + idx * 10)])
+            .collect::<Vec<_>>();
+        assert_eq!(burst, expected);
+
+        // Until the queue is full
+        let mut scheduler = Scheduler::new(scheduler.impairment, 0);
+        let burst = (0..100)
+            .map(|_idx| scheduler.schedule(ms(0), 1000))
+            .collect::<Vec<_>>();
+        assert!(burst[..51].iter().all(|releases| releases.len() == 1));
+        assert!(burst[51..].iter().all(|releases| releases.is_empty()));
+
+        // And it drains again
+        assert_eq!(scheduler.schedule(ms(600), 1000), vec![ms(610)]);
+    }
+
+    #[test]
+    fn bandwidth_and_delay() {
+        let impairment = Impairment {
+            delay: 50,
+            bandwidth: 800,
+            ..Default::default()
+        };
+        let mut scheduler = Scheduler::new(impairment, 0);
+        assert_eq!(scheduler.schedule(ms(0), 1000), vec![ms(60)]);
+        assert_eq!(scheduler.schedule(ms(0), 1000), vec![ms(70)]);
+    }
+}
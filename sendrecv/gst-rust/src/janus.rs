@@ -0,0 +1,718 @@
+// Signalling via the VideoRoom plugin of the Janus WebRTC server
+//
+// The JSON messages of the application are translated from and to the Janus WebSocket API.
+// Depending on the arguments we either publish our streams into the room, or subscribe to the
+// streams of another publisher in the room.
+
+use std::time::Duration;
+
+use rand::distributions::Alphanumeric;
+use rand::prelude::*;
+
+use async_std::task;
+use futures::channel::mpsc;
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{self, FusedStream, Stream, StreamExt};
+
+use async_tungstenite::tungstenite;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Error as WsError;
+use tungstenite::Message as WsMessage;
+
+use serde_json::{json, Value};
+
+use anyhow::{anyhow, bail};
+
+use crate::{tls_connector, Args, JsonMsg};
+
+// Janus destroys sessions that had no activity for 60s by default
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(25);
+
+// Our name as shown to the other participants of the room
+const DISPLAY_NAME: &str = "gstreamer-rs";
+
+// Session and plugin handle we got from Janus
+#[derive(Debug)]
+struct Janus {
+    room: u64,
+    session_id: u64,
+    handle_id: u64,
+}
+
+// Create a new random transaction id for matching replies to our requests
+fn transaction_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .collect()
+}
+
+// The VideoRoom plugin reports its errors inside otherwise successful replies
+fn check_plugin_error(msg: &Value) -> Result<(), anyhow::Error> {
+    let data = &msg["plugindata"]["data"];
+    if let Some(error) = data["error"].as_str() {
+        bail!("VideoRoom error {}: {}", data["error_code"], error);
+    }
+
+    Ok(())
+}
+
+// Send a request to Janus and wait for the reply to it. Replies to plugin messages come
+// asynchronously after an ack, so those are skipped
+async fn transact<Si, St>(
+    ws_sink: &mut Si,
+    ws_stream: &mut St,
+    mut msg: Value,
+) -> Result<Value, anyhow::Error>
+where
+    Si: Sink<WsMessage, Error = WsError> + Unpin,
+    St: Stream<Item = Result<WsMessage, WsError>> + Unpin,
+{
+    let transaction = transaction_id();
+    msg["transaction"] = json!(transaction);
+    ws_sink.send(WsMessage::Text(msg.to_string())).await?;
+
+    loop {
+        let msg = ws_stream
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("Janus closed the connection"))??;
+
+        let text = match msg {
+            WsMessage::Text(text) => text,
+            _ => continue,
+        };
+
+        let reply: Value = serde_json::from_str(&text)?;
+        if reply["transaction"] != json!(transaction) {
+            println!("Ignoring unrelated message from Janus: {}", text);
+            continue;
+        }
+
+        match reply["janus"].as_str() {
+            Some("ack") => continue,
+            Some("error") => bail!("Janus request failed: {}", reply["error"]["reason"]),
+            _ => {
+                check_plugin_error(&reply)?;
+                return Ok(reply);
+            }
+        }
+    }
+}
+
+impl Janus {
+    // Wrap a message body for the VideoRoom plugin into a Janus request
+    fn message(&self, body: Value) -> Value {
+        json!({
+            "janus": "message",
+            "session_id": self.session_id,
+            "handle_id": self.handle_id,
+            "transaction": transaction_id(),
+            "body": body,
+        })
+    }
+
+    // Translate a message from the application into a Janus request
+    fn handle_app_message(&self, msg: &str) -> Result<Value, anyhow::Error> {
+        let json_msg: JsonMsg = serde_json::from_str(msg)?;
+
+        match json_msg {
+            JsonMsg::Sdp { type_, sdp } => {
+                // Publishers send their offers with a configure request, subscribers their
+                // answer to the offer from Janus with a start request
+                let body = if type_ == "offer" {
+                    json!({ "request": "configure", "audio": true, "video": true })
+                } else {
+                    json!({ "request": "start", "room": self.room })
+                };
+
+                let mut msg = self.message(body);
+                msg["jsep"] = json!({ "type": type_, "sdp": sdp });
+
+                Ok(msg)
+            }
+            JsonMsg::Ice {
+                candidate,
+                sdp_mline_index,
+            } => Ok(json!({
+                "janus": "trickle",
+                "session_id": self.session_id,
+                "handle_id": self.handle_id,
+                "transaction": transaction_id(),
+                "candidate": { "candidate": candidate, "sdpMLineIndex": sdp_mline_index },
+            })),
+        }
+    }
+
+    // Handle an asynchronous message from Janus and pass SDP and ICE candidates on to the
+    // application. Returns false once Janus has hung up on us
+    fn handle_janus_message(
+        &self,
+        msg: &str,
+        to_app: &mpsc::UnboundedSender<WsMessage>,
+    ) -> Result<bool, anyhow::Error> {
+        let msg: Value = serde_json::from_str(msg)?;
+
+        match msg["janus"].as_str() {
+            Some("event") => {
+                check_plugin_error(&msg)?;
+                print_room_event(&msg["plugindata"]["data"]);
+
+                if let Some(jsep) = msg.get("jsep") {
+                    forward_jsep(jsep, to_app)?;
+                }
+            }
+            Some("trickle") => {
+                let candidate = &msg["candidate"];
+
+                // The end of candidates needs no handling
+                if candidate["completed"] == json!(true) {
+                    return Ok(true);
+                }
+
+                let json_msg = JsonMsg::Ice {
+                    candidate: candidate["candidate"]
+                        .as_str()
+                        .ok_or_else(|| anyhow!("No candidate in {}", msg))?
+                        .to_string(),
+                    sdp_mline_index: candidate["sdpMLineIndex"]
+                        .as_u64()
+                        .ok_or_else(|| anyhow!("No sdpMLineIndex in {}", msg))?
+                        as u32,
+                };
+
+                to_app
+                    .unbounded_send(WsMessage::Text(serde_json::to_string(&json_msg).unwrap()))?;
+            }
+            Some("webrtcup") => println!("Janus: PeerConnection is up"),
+            Some("media") => println!(
+                "Janus: receiving {} is now {}",
+                msg["type"], msg["receiving"]
+            ),
+            Some("slowlink") => println!("Janus: slow link, {} packets lost", msg["lost"]),
+            Some("hangup") => {
+                println!("Janus hung up: {}", msg["reason"]);
+                return Ok(false);
+            }
+            Some("detached") => {
+                println!("Janus detached our handle");
+                return Ok(false);
+            }
+            Some("timeout") => bail!("Janus session timed out"),
+            Some("error") => bail!("Janus error: {}", msg["error"]["reason"]),
+            _ => (),
+        }
+
+        Ok(true)
+    }
+}
+
+// Pass an SDP offer or answer from Janus on to the application
+fn forward_jsep(
+    jsep: &Value,
+    to_app: &mpsc::UnboundedSender<WsMessage>,
+) -> Result<(), anyhow::Error> {
+    let json_msg = JsonMsg::Sdp {
+        type_: jsep["type"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No SDP type in {}", jsep))?
+            .to_string(),
+        sdp: jsep["sdp"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No SDP in {}", jsep))?
+            .to_string(),
+    };
+
+    to_app.unbounded_send(WsMessage::Text(serde_json::to_string(&json_msg).unwrap()))?;
+
+    Ok(())
+}
+
+// Print changes of the publishers in the room, for knowing which feeds can be subscribed to
+fn print_room_event(data: &Value) {
+    if let Some(publishers) = data["publishers"].as_array() {
+        for publisher in publishers {
+            println!(
+                "Publisher {} ({}) is available",
+                publisher["id"], publisher["display"]
+            );
+        }
+    }
+
+    if let Some(unpublished) = data.get("unpublished") {
+        println!("Publisher {} stopped publishing", unpublished);
+    }
+
+    if let Some(leaving) = data.get("leaving") {
+        println!("Publisher {} left the room", leaving);
+    }
+}
+
+// Connect to Janus, join the room and then translate messages between Janus and the application
+// until either side is done
+pub async fn run(
+    args: Args,
+    from_app: mpsc::UnboundedReceiver<WsMessage>,
+    to_app: mpsc::UnboundedSender<WsMessage>,
+) -> Result<(), anyhow::Error> {
+    // Janus only accepts connections that use its own subprotocol
+    let mut request = args.server.as_str().into_client_request()?;
+    request
+        .headers_mut()
+        .insert("sec-websocket-protocol", "janus-protocol".parse()?);
+
+    let (ws, _) = async_tungstenite::async_std::connect_async_with_tls_connector(
+        request,
+        tls_connector(&args)?,
+    )
+    .await?;
+
+    println!("connected to Janus");
+
+    // Split the websocket into the Sink and Stream
+    let (ws_sink, ws_stream) = ws.split();
+    // Fuse the Stream, required for the select macro
+    let ws_stream = ws_stream.fuse();
+
+    run_session(
+        args.janus_room,
+        args.janus_feed,
+        ws_sink,
+        ws_stream,
+        from_app,
+        to_app,
+    )
+    .await
+}
+
+// Join the room, or subscribe to the given feed in it, via an established connection to Janus
+async fn run_session<Si, St>(
+    room: u64,
+    feed: Option<u64>,
+    mut ws_sink: Si,
+    mut ws_stream: St,
+    mut from_app: mpsc::UnboundedReceiver<WsMessage>,
+    to_app: mpsc::UnboundedSender<WsMessage>,
+) -> Result<(), anyhow::Error>
+where
+    Si: Sink<WsMessage, Error = WsError> + Unpin,
+    St: Stream<Item = Result<WsMessage, WsError>> + FusedStream + Unpin,
+{
+    let reply = transact(&mut ws_sink, &mut ws_stream, json!({ "janus": "create" })).await?;
+    let session_id = reply["data"]["id"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("No session id in {}", reply))?;
+
+    let reply = transact(
+        &mut ws_sink,
+        &mut ws_stream,
+        json!({
+            "janus": "attach",
+            "session_id": session_id,
+            "plugin": "janus.plugin.videoroom",
+        }),
+    )
+    .await?;
+    let handle_id = reply["data"]["id"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("No handle id in {}", reply))?;
+
+    let janus = Janus {
+        room,
+        session_id,
+        handle_id,
+    };
+
+    // Either subscribe to the given feed, in which case Janus sends us an offer, or join as a
+    // publisher and send our own offer once the application created it
+    let join = match feed {
+        Some(feed) => json!({
+            "request": "join",
+            "ptype": "subscriber",
+            "room": janus.room,
+            "feed": feed,
+        }),
+        None => json!({
+            "request": "join",
+            "ptype": "publisher",
+            "room": janus.room,
+            "display": DISPLAY_NAME,
+        }),
+    };
+
+    let reply = transact(&mut ws_sink, &mut ws_stream, janus.message(join)).await?;
+    let data = &reply["plugindata"]["data"];
+    match feed {
+        Some(feed) => println!("Subscribed to feed {} in room {}", feed, janus.room),
+        None => println!("Joined room {} as publisher {}", janus.room, data["id"]),
+    }
+    print_room_event(data);
+
+    if let Some(jsep) = reply.get("jsep") {
+        forward_jsep(jsep, &to_app)?;
+    }
+
+    // Keep the session alive as long as we're running
+    let mut keepalive = stream::unfold((), |()| async {
+        task::sleep(KEEPALIVE_INTERVAL).await;
+        Some(((), ()))
+    })
+    .boxed()
+    .fuse();
+
+    loop {
+        let ws_msg = futures::select! {
+            // Handle the messages from Janus here
+            ws_msg = ws_stream.select_next_some() => {
+                match ws_msg? {
+                    WsMessage::Close(_) => {
+                        println!("Janus closed the connection");
+                        break
+                    },
+                    WsMessage::Ping(data) => Some(WsMessage::Pong(data)),
+                    WsMessage::Text(text) => {
+                        if !janus.handle_janus_message(&text, &to_app)? {
+                            break
+                        }
+                        None
+                    },
+                    _ => None,
+                }
+            },
+            // Translate the messages of the application into Janus requests
+            app_msg = from_app.next() => {
+                match app_msg {
+                    Some(WsMessage::Text(text)) => {
+                        Some(WsMessage::Text(janus.handle_app_message(&text)?.to_string()))
+                    },
+                    Some(_) => None,
+                    // The application is shutting down
+                    None => break,
+                }
+            },
+            _ = keepalive.select_next_some() => {
+                Some(WsMessage::Text(json!({
+                    "janus": "keepalive",
+                    "session_id": janus.session_id,
+                    "transaction": transaction_id(),
+                }).to_string()))
+            },
+        };
+
+        // If there's a message to send out, do so now
+        if let Some(ws_msg) = ws_msg {
+            ws_sink.send(ws_msg).await?;
+        }
+    }
+
+    // Leave the room by destroying our session, and let the application know that we're done
+    let _ = ws_sink
+        .send(WsMessage::Text(
+            json!({
+                "janus": "destroy",
+                "session_id": janus.session_id,
+                "transaction": transaction_id(),
+            })
+            .to_string(),
+        ))
+        .await;
+    let _ = ws_sink.close().await;
+
+    let _ = to_app.unbounded_send(WsMessage::Close(None));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n";
+    const ANSWER: &str = "v=0\r\no=- 1 0 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n";
+    const CANDIDATE: &str = "candidate:1 1 UDP 2015363327 127.0.0.1 5000 typ host";
+
+    fn parse(msg: WsMessage) -> Value {
+        match msg {
+            WsMessage::Text(text) => serde_json::from_str(&text).unwrap(),
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+    }
+
+    fn text(json_msg: &JsonMsg) -> WsMessage {
+        WsMessage::Text(serde_json::to_string(json_msg).unwrap())
+    }
+
+    // Reply to one of our requests like Janus would, with the same transaction id
+    fn reply(request: &Value, mut reply: Value) -> WsMessage {
+        reply["transaction"] = request["transaction"].clone();
+        WsMessage::Text(reply.to_string())
+    }
+
+    // Create the session and attach to the VideoRoom plugin like Janus would, with session id 1
+    // and handle id 2
+    async fn attach(
+        janus_tx: &mpsc::UnboundedSender<WsMessage>,
+        janus_rx: &mut mpsc::UnboundedReceiver<WsMessage>,
+    ) {
+        let request = parse(janus_rx.next().await.unwrap());
+        assert_eq!(request["janus"], "create");
+        janus_tx
+            .unbounded_send(reply(
+                &request,
+                json!({ "janus": "success", "data": { "id": 1 } }),
+            ))
+            .unwrap();
+
+        let request = parse(janus_rx.next().await.unwrap());
+        assert_eq!(request["janus"], "attach");
+        assert_eq!(request["session_id"], 1);
+        assert_eq!(request["plugin"], "janus.plugin.videoroom");
+        janus_tx
+            .unbounded_send(reply(
+                &request,
+                json!({ "janus": "success", "data": { "id": 2 } }),
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn subscribe_to_feed() {
+        task::block_on(async {
+            // Both directions of the WebSocket connection to the mocked Janus, and the channels
+            // to the application
+            let (janus_tx, ws_stream) = mpsc::unbounded::<WsMessage>();
+            let (ws_sink, mut janus_rx) = mpsc::unbounded::<WsMessage>();
+            let (app_tx, from_app) = mpsc::unbounded::<WsMessage>();
+            let (to_app, mut app_rx) = mpsc::unbounded::<WsMessage>();
+
+            let backend = task::spawn(run_session(
+                1234,
+                Some(42),
+                ws_sink.sink_map_err(|_| WsError::ConnectionClosed),
+                ws_stream.map(Ok).fuse(),
+                from_app,
+                to_app,
+            ));
+
+            attach(&janus_tx, &mut janus_rx).await;
+
+            // Janus acks the join first and then sends its offer with the actual reply
+            let request = parse(janus_rx.next().await.unwrap());
+            assert_eq!(request["janus"], "message");
+            assert_eq!(request["handle_id"], 2);
+            assert_eq!(
+                request["body"],
+                json!({ "request": "join", "ptype": "subscriber", "room": 1234, "feed": 42 })
+            );
+            janus_tx
+                .unbounded_send(reply(&request, json!({ "janus": "ack" })))
+                .unwrap();
+            janus_tx
+                .unbounded_send(reply(
+                    &request,
+                    json!({
+                        "janus": "event",
+                        "plugindata": {
+                            "plugin": "janus.plugin.videoroom",
+                            "data": { "videoroom": "attached", "room": 1234, "id": 42 },
+                        },
+                        "jsep": { "type": "offer", "sdp": OFFER },
+                    }),
+                ))
+                .unwrap();
+
+            assert_eq!(
+                app_rx.next().await.unwrap(),
+                text(&JsonMsg::Sdp {
+                    type_: "offer".to_string(),
+                    sdp: OFFER.to_string(),
+                })
+            );
+
+            // Candidates of Janus are passed on, the end of candidates is not
+            for candidate in &[
+                json!({ "candidate": CANDIDATE, "sdpMid": "0", "sdpMLineIndex": 0 }),
+                json!({ "completed": true }),
+            ] {
+                janus_tx
+                    .unbounded_send(WsMessage::Text(
+                        json!({
+                            "janus": "trickle",
+                            "session_id": 1,
+                            "sender": 2,
+                            "candidate": candidate,
+                        })
+                        .to_string(),
+                    ))
+                    .unwrap();
+            }
+
+            assert_eq!(
+                app_rx.next().await.unwrap(),
+                text(&JsonMsg::Ice {
+                    candidate: CANDIDATE.to_string(),
+                    sdp_mline_index: 0,
+                })
+            );
+
+            // Our answer starts the subscription
+            app_tx
+                .unbounded_send(text(&JsonMsg::Sdp {
+                    type_: "answer".to_string(),
+                    sdp: ANSWER.to_string(),
+                }))
+                .unwrap();
+
+            let request = parse(janus_rx.next().await.unwrap());
+            assert_eq!(request["janus"], "message");
+            assert_eq!(request["body"], json!({ "request": "start", "room": 1234 }));
+            assert_eq!(request["jsep"], json!({ "type": "answer", "sdp": ANSWER }));
+
+            // And once Janus hangs up, the session is destroyed and the application told so
+            janus_tx
+                .unbounded_send(WsMessage::Text(
+                    json!({
+                        "janus": "hangup",
+                        "session_id": 1,
+                        "sender": 2,
+                        "reason": "Close PC",
+                    })
+                    .to_string(),
+                ))
+                .unwrap();
+
+            let request = parse(janus_rx.next().await.unwrap());
+            assert_eq!(request["janus"], "destroy");
+            assert_eq!(request["session_id"], 1);
+
+            assert_eq!(app_rx.next().await.unwrap(), WsMessage::Close(None));
+
+            backend.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn publish() {
+        task::block_on(async {
+            let (janus_tx, ws_stream) = mpsc::unbounded::<WsMessage>();
+            let (ws_sink, mut janus_rx) = mpsc::unbounded::<WsMessage>();
+            let (app_tx, from_app) = mpsc::unbounded::<WsMessage>();
+            let (to_app, mut app_rx) = mpsc::unbounded::<WsMessage>();
+
+            let backend = task::spawn(run_session(
+                1234,
+                None,
+                ws_sink.sink_map_err(|_| WsError::ConnectionClosed),
+                ws_stream.map(Ok).fuse(),
+                from_app,
+                to_app,
+            ));
+
+            attach(&janus_tx, &mut janus_rx).await;
+
+            let request = parse(janus_rx.next().await.unwrap());
+            assert_eq!(request["janus"], "message");
+            assert_eq!(request["handle_id"], 2);
+            assert_eq!(
+                request["body"],
+                json!({
+                    "request": "join",
+                    "ptype": "publisher",
+                    "room": 1234,
+                    "display": DISPLAY_NAME,
+                })
+            );
+            janus_tx
+                .unbounded_send(reply(&request, json!({ "janus": "ack" })))
+                .unwrap();
+            janus_tx
+                .unbounded_send(reply(
+                    &request,
+                    json!({
+                        "janus": "event",
+                        "plugindata": {
+                            "plugin": "janus.plugin.videoroom",
+                            "data": {
+                                "videoroom": "joined",
+                                "room": 1234,
+                                "id": 7,
+                                "publishers": [{ "id": 42, "display": "other" }],
+                            },
+                        },
+                    }),
+                ))
+                .unwrap();
+
+            // Our offer is sent with a configure request
+            app_tx
+                .unbounded_send(text(&JsonMsg::Sdp {
+                    type_: "offer".to_string(),
+                    sdp: OFFER.to_string(),
+                }))
+                .unwrap();
+
+            let request = parse(janus_rx.next().await.unwrap());
+            assert_eq!(request["janus"], "message");
+            assert_eq!(request["session_id"], 1);
+            assert_eq!(request["handle_id"], 2);
+            assert_eq!(
+                request["body"],
+                json!({ "request": "configure", "audio": true, "video": true })
+            );
+            assert_eq!(request["jsep"], json!({ "type": "offer", "sdp": OFFER }));
+
+            // And our candidates are trickled on the publisher handle
+            app_tx
+                .unbounded_send(text(&JsonMsg::Ice {
+                    candidate: CANDIDATE.to_string(),
+                    sdp_mline_index: 1,
+                }))
+                .unwrap();
+
+            let request = parse(janus_rx.next().await.unwrap());
+            assert_eq!(request["janus"], "trickle");
+            assert_eq!(request["session_id"], 1);
+            assert_eq!(request["handle_id"], 2);
+            assert_eq!(
+                request["candidate"],
+                json!({ "candidate": CANDIDATE, "sdpMLineIndex": 1 })
+            );
+
+            // Janus answers asynchronously with an event
+            janus_tx
+                .unbounded_send(WsMessage::Text(
+                    json!({
+                        "janus": "event",
+                        "session_id": 1,
+                        "sender": 2,
+                        "plugindata": {
+                            "plugin": "janus.plugin.videoroom",
+                            "data": { "videoroom": "event", "room": 1234, "configured": "ok" },
+                        },
+                        "jsep": { "type": "answer", "sdp": ANSWER },
+                    })
+                    .to_string(),
+                ))
+                .unwrap();
+
+            assert_eq!(
+                app_rx.next().await.unwrap(),
+                text(&JsonMsg::Sdp {
+                    type_: "answer".to_string(),
+                    sdp: ANSWER.to_string(),
+                })
+            );
+
+            // Once the application shuts down, the session is destroyed
+            drop(app_tx);
+
+            let request = parse(janus_rx.next().await.unwrap());
+            assert_eq!(request["janus"], "destroy");
+            assert_eq!(request["session_id"], 1);
+
+            assert_eq!(app_rx.next().await.unwrap(), WsMessage::Close(None));
+
+            backend.await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,160 @@
+// Measurement of the end-to-end latency of the video
+//
+// The sender draws the current wall clock time as a pattern of black and white blocks into the
+// bottom left corner of every frame right before encoding it, and the receiver reads it again
+// from the decoded frames. Both sides need the same wall clock, i.e. run on the same host or have
+// their clocks synchronized e.g. via NTP.
+
+use std::time::SystemTime;
+
+use gst::prelude::*;
+
+// Only the lower bits of the time in milliseconds fit into the pattern, which wraps around every
+// 4.6 hours but is more than enough for the difference between both sides
+const DATA_BITS: u32 = 24;
+const DATA_MASK: u64 = (1 << DATA_BITS) - 1;
+
+// Each bit is a block of this many pixels, so that it survives lossy encoding. With the blocks of
+// the synchronization pattern the video has to be at least 224 pixels wide
+const PATTERN_SETTINGS: &str = "pattern-width=8 pattern-height=16 pattern-count=4";
+
+// Pipeline description for drawing the timestamps into raw video, including the link to the
+// next element
+pub fn mark_description() -> String {
+    format!(
+        "simplevideomark name=latency-mark {} pattern-data-count={} ! ",
+        PATTERN_SETTINGS, DATA_BITS
+    )
+}
+
+// Pipeline description for reading the timestamps from raw video again, including the link to the
+// next element
+pub fn detect_description() -> String {
+    format!(
+        "simplevideomarkdetect name=latency-detect {} pattern-data-count={} ! ",
+        PATTERN_SETTINGS, DATA_BITS
+    )
+}
+
+fn now() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    now.as_millis() as u64 & DATA_MASK
+}
+
+// Update the timestamp of the mark element for every frame passing through it
+pub fn stamp_frames(mark: &gst::Element) {
+    let sink_pad = mark.get_static_pad("sink").unwrap();
+    sink_pad
+        .add_probe(gst::PadProbeType::BUFFER, |pad, _info| {
+            if let Some(mark) = pad.get_parent_element() {
+                mark.set_property("pattern-data", &now()).unwrap();
+            }
+
+            gst::PadProbeReturn::Ok
+        })
+        .unwrap();
+}
+
+// Latency in milliseconds of the frame a message of the detect element was posted for, if it
+// carried a timestamp
+pub fn parse_detection(s: &gst::StructureRef) -> Option<u64> {
+    if s.get_name() != "GstSimpleVideoMarkDetect" {
+        return None;
+    }
+
+    if !s.get_some::<bool>("have-pattern").unwrap_or(false) {
+        return None;
+    }
+
+    let stamp = s.get_some::<u64>("data").ok()?;
+    latency(now(), stamp)
+}
+
+// Difference between the lower bits of the current time and of a timestamp, across the wraparound
+fn latency(now: u64, stamp: u64) -> Option<u64> {
+    let latency = now.wrapping_sub(stamp) & DATA_MASK;
+
+    // Frames from the future mean that the clocks of both sides differ
+    if latency > DATA_MASK / 2 {
+        return None;
+    }
+
+    Some(latency)
+}
+
+// Latencies measured since the last report
+#[derive(Debug, Default)]
+pub struct LatencyStats {
+    samples: Vec<u64>,
+}
+
+impl LatencyStats {
+    pub fn add(&mut self, latency: u64) {
+        self.samples.push(latency);
+    }
+
+    // Summary of the percentiles of all latencies since the last time, which are then forgotten
+    pub fn report(&mut self) -> Option<String> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut samples = std::mem::take(&mut self.samples);
+        samples.sort_unstable();
+
+        let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+
+        Some(format!(
+            "median {} ms, 90% {} ms, 99% {} ms, max {} ms over {} frames",
+            percentile(50),
+            percentile(90),
+            percentile(99),
+            samples[samples.len() - 1],
+            samples.len()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_wraparound() {
+        assert_eq!(latency(1100, 1000), Some(100));
+        assert_eq!(latency(1000, 1000), Some(0));
+
+        // The current time already wrapped around, the timestamp not yet
+        assert_eq!(latency(50, DATA_MASK - 49), Some(100));
+        assert_eq!(latency(0, DATA_MASK), Some(1));
+
+        // Timestamps from the future
+        assert_eq!(latency(1000, 1100), None);
+        assert_eq!(latency(DATA_MASK - 49, 50), None);
+    }
+
+    #[test]
+    fn report_percentiles() {
+        let mut stats = LatencyStats::default();
+        assert_eq!(stats.report(), None);
+
+        for latency in (1..=100).rev() {
+            stats.add(latency);
+        }
+        assert_eq!(
+            stats.report().unwrap(),
+            "median 50 ms, 90% 90 ms, 99% 99 ms, max 100 ms over 100 frames"
+        );
+
+        // Only the latencies since the last report are used
+        assert_eq!(stats.report(), None);
+        stats.add(20);
+        assert_eq!(
+            stats.report().unwrap(),
+            "median 20 ms, 90% 20 ms, 99% 20 ms, max 20 ms over 1 frames"
+        );
+    }
+}
@@ -0,0 +1,227 @@
+// Manual signalling by copying the messages between both sides, e.g. for testing without any
+// signalling server
+//
+// All messages for the remote side are printed as one JSON object per line, and the messages of
+// the remote side are read in the same format from stdin or from a file. Without trickle ICE
+// there is only a single message per side, the SDP including all candidates.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_std::fs;
+use async_std::io::{self, BufReader, SeekFrom};
+use async_std::prelude::*;
+use async_std::task;
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+
+use async_tungstenite::tungstenite;
+use tungstenite::Message as WsMessage;
+
+use anyhow::Context;
+
+use crate::{Args, JsonMsg};
+
+// How often to check if the messages of the remote side were written to the file
+const FILE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Pass a line with a message from the remote side on to the application
+fn forward_line(
+    line: &str,
+    to_app: &mpsc::UnboundedSender<WsMessage>,
+) -> Result<(), anyhow::Error> {
+    let line = line.trim();
+
+    // Skip everything that is not a message, e.g. log output that was copied along
+    if !line.starts_with('{') {
+        return Ok(());
+    }
+
+    // Check the message here already to give feedback about copy & paste mistakes
+    serde_json::from_str::<JsonMsg>(line).with_context(|| format!("Invalid message {}", line))?;
+
+    to_app.unbounded_send(WsMessage::Text(line.to_string()))?;
+
+    Ok(())
+}
+
+// Read the messages of the remote side line by line from stdin
+async fn read_stdin(to_app: mpsc::UnboundedSender<WsMessage>) -> Result<(), anyhow::Error> {
+    println!("Paste the messages of the remote side here");
+
+    let mut lines = BufReader::new(io::stdin()).lines();
+    while let Some(line) = lines.next().await {
+        if let Err(err) = forward_line(&line?, &to_app) {
+            println!("{:?}", err);
+        }
+    }
+
+    Ok(())
+}
+
+// Splits data that is read in chunks into lines. An incomplete line at the end is kept until the
+// rest of it arrives
+#[derive(Debug, Default)]
+struct LineBuffer {
+    data: Vec<u8>,
+}
+
+impl LineBuffer {
+    // Add the next chunk and return all lines that are complete now
+    fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.data.extend_from_slice(data);
+
+        let end = match self.data.iter().rposition(|&byte| byte == b'\n') {
+            Some(pos) => pos + 1,
+            None => return Vec::new(),
+        };
+        let complete = self.data.drain(..end).collect::<Vec<_>>();
+
+        String::from_utf8_lossy(&complete)
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+}
+
+// Read everything that was appended to the file after the given offset, or None if the file
+// doesn't exist yet
+async fn read_appended(path: &Path, offset: u64) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let mut file = match fs::File::open(path).await {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).await?;
+
+    Ok(Some(data))
+}
+
+// Follow the file with the remote side's messages and pass on every line once it was written
+// completely. With trickle ICE the remote side keeps appending candidates after its SDP
+async fn read_file(
+    path: PathBuf,
+    to_app: mpsc::UnboundedSender<WsMessage>,
+) -> Result<(), anyhow::Error> {
+    println!(
+        "Waiting for the messages of the remote side in {}",
+        path.display()
+    );
+
+    let mut offset = 0;
+    let mut lines = LineBuffer::default();
+
+    // Until the application shuts down
+    while !to_app.is_closed() {
+        // Start again from the beginning if the file was replaced by a shorter one
+        let len = fs::metadata(&path)
+            .await
+            .map_or(0, |metadata| metadata.len());
+        if len < offset {
+            offset = 0;
+            lines = LineBuffer::default();
+        }
+
+        if let Some(data) = read_appended(&path, offset).await? {
+            offset += data.len() as u64;
+
+            for line in lines.push(&data) {
+                if let Err(err) = forward_line(&line, &to_app) {
+                    println!("{:?}", err);
+                }
+            }
+        }
+
+        task::sleep(FILE_POLL_INTERVAL).await;
+    }
+
+    Ok(())
+}
+
+// Print the messages of the application until it shuts down, while the messages of the remote
+// side are read in the background
+pub async fn run(
+    args: Args,
+    mut from_app: mpsc::UnboundedReceiver<WsMessage>,
+    to_app: mpsc::UnboundedSender<WsMessage>,
+) -> Result<(), anyhow::Error> {
+    task::spawn(async move {
+        let res = match args.remote_file {
+            Some(path) => read_file(path, to_app).await,
+            None => read_stdin(to_app).await,
+        };
+
+        if let Err(err) = res {
+            println!("Failed to read messages of the remote side: {:?}", err);
+        }
+    });
+
+    println!("Copy the lines starting with {{ to the remote side");
+
+    while let Some(msg) = from_app.next().await {
+        if let WsMessage::Text(text) = msg {
+            println!("{}", text);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    const ICE: &str = r#"{"ice":{"candidate":"candidate:1 1 UDP 2015363327 127.0.0.1 5000 typ host","sdpMLineIndex":0}}"#;
+    const SDP: &str = r#"{"sdp":{"type":"offer","sdp":"v=0\r\n"}}"#;
+
+    #[test]
+    fn split_lines() {
+        let mut lines = LineBuffer::default();
+
+        assert!(lines.push(b"{\"sdp\"").is_empty());
+        assert_eq!(lines.push(b":1}\n{\"ice\""), vec!["{\"sdp\":1}"]);
+        assert_eq!(lines.push(b":2}\r\n\nrest"), vec!["{\"ice\":2}", ""]);
+        assert_eq!(lines.push(b"\n"), vec!["rest"]);
+    }
+
+    #[test]
+    fn follow_file() {
+        let path = std::env::temp_dir().join(format!("remote-{}.txt", uuid::Uuid::new_v4()));
+
+        // The first message is complete, the second one only half written
+        let mut file = std::fs::File::create(&path).unwrap();
+        write!(file, "{}\n{}", SDP, &ICE[..20]).unwrap();
+
+        task::block_on(async {
+            let (to_app, mut app_rx) = mpsc::unbounded::<WsMessage>();
+            let reader = task::spawn(read_file(path.clone(), to_app));
+
+            assert_eq!(
+                app_rx.next().await.unwrap(),
+                WsMessage::Text(SDP.to_string())
+            );
+
+            // Once the rest of the message is appended after the first read it is passed on too
+            task::sleep(2 * FILE_POLL_INTERVAL).await;
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            writeln!(file, "{}", &ICE[20..]).unwrap();
+
+            assert_eq!(
+                app_rx.next().await.unwrap(),
+                WsMessage::Text(ICE.to_string())
+            );
+
+            // And reading stops once the application is gone
+            drop(app_rx);
+            reader.await.unwrap();
+        });
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
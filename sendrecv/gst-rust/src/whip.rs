@@ -0,0 +1,552 @@
+// Signalling via the WebRTC-HTTP Ingestion Protocol (WHIP) for publishing, or its counterpart for
+// playback, the WebRTC-HTTP Egress Protocol (WHEP)
+//
+// Both work the same way: our offer is POSTed to the endpoint, which replies with the answer and
+// the URL of the newly created resource. ICE candidates are trickled to the resource with PATCH
+// requests and the resource is DELETEd again once we shut down.
+
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+
+use async_tungstenite::tungstenite;
+use tungstenite::Message as WsMessage;
+
+use surf::{StatusCode, Url};
+
+use anyhow::{anyhow, bail};
+
+use crate::{Args, JsonMsg, Signalling};
+
+// ICE credentials and media sections of our offer, needed for trickling candidates
+#[derive(Debug, Default)]
+struct OfferInfo {
+    ice_ufrag: String,
+    ice_pwd: String,
+    // Media line and mid of each media section
+    media: Vec<(String, String)>,
+}
+
+impl OfferInfo {
+    fn parse(sdp: &str) -> Self {
+        let mut info = OfferInfo::default();
+
+        for line in sdp.lines() {
+            if line.starts_with("m=") {
+                info.media.push((line.to_string(), String::new()));
+            } else if line.starts_with("a=mid:") {
+                if let Some(media) = info.media.last_mut() {
+                    media.1 = line["a=mid:".len()..].to_string();
+                }
+            } else if line.starts_with("a=ice-ufrag:") && info.ice_ufrag.is_empty() {
+                info.ice_ufrag = line["a=ice-ufrag:".len()..].to_string();
+            } else if line.starts_with("a=ice-pwd:") && info.ice_pwd.is_empty() {
+                info.ice_pwd = line["a=ice-pwd:".len()..].to_string();
+            }
+        }
+
+        info
+    }
+
+    // Create an SDP fragment for trickling a single candidate of the given media section
+    fn sdp_fragment(&self, sdp_mline_index: u32, candidate: &str) -> Result<String, anyhow::Error> {
+        let (media, mid) = self
+            .media
+            .get(sdp_mline_index as usize)
+            .ok_or_else(|| anyhow!("No media section {} in our offer", sdp_mline_index))?;
+
+        Ok(format!(
+            "a=ice-ufrag:{}\r\na=ice-pwd:{}\r\n{}\r\na=mid:{}\r\na={}\r\n",
+            self.ice_ufrag, self.ice_pwd, media, mid, candidate
+        ))
+    }
+}
+
+// State of our session with the WHIP or WHEP endpoint
+#[derive(Debug)]
+struct Whip {
+    // Name of the protocol for messages
+    protocol: &'static str,
+    endpoint: Url,
+    token: Option<String>,
+    offer: Option<OfferInfo>,
+    // URL and ETag of the resource created for our session
+    resource: Option<Url>,
+    etag: Option<String>,
+    // Candidates gathered before the resource was created
+    pending_candidates: Vec<(u32, String)>,
+}
+
+impl Whip {
+    // Add the bearer token to a request, if we have one
+    fn authorize(&self, request: surf::RequestBuilder) -> surf::RequestBuilder {
+        match self.token {
+            Some(ref token) => request.header("Authorization", format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    // Handle a message from the application and pass it on to the endpoint
+    async fn handle_app_message(
+        &mut self,
+        msg: &str,
+        to_app: &mpsc::UnboundedSender<WsMessage>,
+    ) -> Result<(), anyhow::Error> {
+        let json_msg: JsonMsg = serde_json::from_str(msg)?;
+
+        match json_msg {
+            JsonMsg::Sdp { type_, sdp } => {
+                if type_ != "offer" {
+                    bail!("Sdp type is not \"offer\" but \"{}\"", type_);
+                }
+
+                if self.offer.is_some() {
+                    println!(
+                        "{} does not support renegotiation, ignoring new offer",
+                        self.protocol
+                    );
+                    return Ok(());
+                }
+
+                self.offer = Some(OfferInfo::parse(&sdp));
+
+                let answer = self.send_offer(sdp).await?;
+
+                let json_msg = JsonMsg::Sdp {
+                    type_: "answer".to_string(),
+                    sdp: answer,
+                };
+                to_app
+                    .unbounded_send(WsMessage::Text(serde_json::to_string(&json_msg).unwrap()))?;
+
+                // Now that we know the resource, trickle all candidates we gathered so far
+                for (sdp_mline_index, candidate) in std::mem::take(&mut self.pending_candidates) {
+                    self.send_candidate(sdp_mline_index, &candidate).await?;
+                }
+            }
+            JsonMsg::Ice {
+                candidate,
+                sdp_mline_index,
+            } => {
+                if self.resource.is_some() {
+                    self.send_candidate(sdp_mline_index, &candidate).await?;
+                } else {
+                    self.pending_candidates.push((sdp_mline_index, candidate));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // POST our offer to the endpoint and return the answer from its reply
+    async fn send_offer(&mut self, offer: String) -> Result<String, anyhow::Error> {
+        println!(
+            "Sending offer to {} endpoint {}",
+            self.protocol, self.endpoint
+        );
+
+        let mut response = self
+            .authorize(surf::post(&self.endpoint))
+            .header("Content-Type", "application/sdp")
+            .body(offer)
+            .await
+            .map_err(|err| anyhow!("Failed to send offer: {}", err))?;
+
+        let answer = response
+            .body_string()
+            .await
+            .map_err(|err| anyhow!("Failed to read answer: {}", err))?;
+
+        if response.status() != StatusCode::Created {
+            bail!(
+                "{} endpoint rejected offer: {} {}",
+                self.protocol,
+                response.status(),
+                answer
+            );
+        }
+
+        // The resource URL may be relative to the endpoint
+        let location = response
+            .header("Location")
+            .ok_or_else(|| anyhow!("No resource location in {} reply", self.protocol))?
+            .last()
+            .as_str();
+        let resource = self.endpoint.join(location)?;
+        println!("Created {} resource {}", self.protocol, resource);
+
+        self.etag = response
+            .header("ETag")
+            .map(|etag| etag.last().as_str().to_string());
+        self.resource = Some(resource);
+
+        Ok(answer)
+    }
+
+    // PATCH a single ICE candidate to our resource
+    async fn send_candidate(
+        &self,
+        sdp_mline_index: u32,
+        candidate: &str,
+    ) -> Result<(), anyhow::Error> {
+        let resource = self.resource.as_ref().expect("no resource");
+        let fragment = self
+            .offer
+            .as_ref()
+            .expect("no offer")
+            .sdp_fragment(sdp_mline_index, candidate)?;
+
+        let mut request = self
+            .authorize(surf::patch(resource))
+            .header("Content-Type", "application/trickle-ice-sdpfrag")
+            .body(fragment);
+        if let Some(ref etag) = self.etag {
+            request = request.header("If-Match", etag.as_str());
+        }
+
+        let response = request
+            .await
+            .map_err(|err| anyhow!("Failed to send ICE candidate: {}", err))?;
+
+        // Trickling is optional, the endpoint can still find the candidates via connectivity checks
+        if !response.status().is_success() {
+            println!(
+                "{} endpoint didn't accept ICE candidate: {}",
+                self.protocol,
+                response.status()
+            );
+        }
+
+        Ok(())
+    }
+
+    // DELETE our resource again to end the session
+    async fn delete(&self) -> Result<(), anyhow::Error> {
+        let resource = match self.resource {
+            Some(ref resource) => resource,
+            None => return Ok(()),
+        };
+
+        println!("Deleting {} resource {}", self.protocol, resource);
+
+        let response = self
+            .authorize(surf::delete(resource))
+            .await
+            .map_err(|err| anyhow!("Failed to delete resource: {}", err))?;
+
+        if !response.status().is_success() {
+            bail!("Failed to delete resource: {}", response.status());
+        }
+
+        Ok(())
+    }
+}
+
+// Publish to the WHIP endpoint or play from the WHEP endpoint until the application stops or the
+// user quits via quit_rx
+pub async fn run(
+    args: Args,
+    mut from_app: mpsc::UnboundedReceiver<WsMessage>,
+    to_app: mpsc::UnboundedSender<WsMessage>,
+    mut quit_rx: mpsc::UnboundedReceiver<()>,
+) -> Result<(), anyhow::Error> {
+    let mut whip = Whip {
+        protocol: if args.signalling == Signalling::Whep {
+            "WHEP"
+        } else {
+            "WHIP"
+        },
+        endpoint: Url::parse(&args.server)?,
+        token: args.token,
+        offer: None,
+        resource: None,
+        etag: None,
+        pending_candidates: Vec::new(),
+    };
+
+    let res = loop {
+        futures::select! {
+            app_msg = from_app.next() => {
+                match app_msg {
+                    Some(WsMessage::Text(text)) => {
+                        if let Err(err) = whip.handle_app_message(&text, &to_app).await {
+                            break Err(err);
+                        }
+                    },
+                    Some(_) => (),
+                    // The application is shutting down
+                    None => break Ok(()),
+                }
+            },
+            _ = quit_rx.next() => {
+                println!("Shutting down");
+                break Ok(())
+            },
+        }
+    };
+
+    // Also delete the resource if anything failed
+    let delete_res = whip.delete().await;
+
+    // Let the application know that we're done
+    let _ = to_app.unbounded_send(WsMessage::Close(None));
+
+    res.and(delete_res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::io::prelude::*;
+    use async_std::io::{BufReader, BufWriter};
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task;
+
+    use structopt::StructOpt;
+
+    const OFFER: &str = "v=0\r\n\
+        o=- 0 0 IN IP4 127.0.0.1\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        a=group:BUNDLE video0 audio1\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+        a=ice-ufrag:ufrag\r\n\
+        a=ice-pwd:pwd\r\n\
+        a=mid:video0\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 97\r\n\
+        a=ice-ufrag:ufrag\r\n\
+        a=ice-pwd:pwd\r\n\
+        a=mid:audio1\r\n";
+    const ANSWER: &str = "v=0\r\no=- 1 0 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n";
+    const CANDIDATE: &str = "candidate:1 1 UDP 2015363327 127.0.0.1 5000 typ host";
+
+    #[test]
+    fn parse_offer() {
+        let info = OfferInfo::parse(OFFER);
+
+        assert_eq!(info.ice_ufrag, "ufrag");
+        assert_eq!(info.ice_pwd, "pwd");
+        assert_eq!(
+            info.media,
+            vec![
+                (
+                    "m=video 9 UDP/TLS/RTP/SAVPF 96".to_string(),
+                    "video0".to_string()
+                ),
+                (
+                    "m=audio 9 UDP/TLS/RTP/SAVPF 97".to_string(),
+                    "audio1".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn sdp_fragment() {
+        let info = OfferInfo::parse(OFFER);
+
+        // With bundling the candidates of all media sections are gathered for the first one
+        assert_eq!(
+            info.sdp_fragment(0, CANDIDATE).unwrap(),
+            format!(
+                "a=ice-ufrag:ufrag\r\na=ice-pwd:pwd\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+                 a=mid:video0\r\na={}\r\n",
+                CANDIDATE
+            )
+        );
+        assert!(info
+            .sdp_fragment(1, CANDIDATE)
+            .unwrap()
+            .contains("a=mid:audio1\r\n"));
+
+        assert!(info.sdp_fragment(2, CANDIDATE).is_err());
+    }
+
+    // A request as received by the endpoint
+    #[derive(Debug)]
+    struct Request {
+        method: String,
+        path: String,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Request {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    // Read a single HTTP/1.1 request, or None once the client closed the connection
+    async fn read_request(reader: &mut BufReader<TcpStream>) -> Option<Request> {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let mut split = line.split_whitespace();
+        let method = split.next()?.to_string();
+        let path = split.next()?.to_string();
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.ok()?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let mut split = line.splitn(2, ':');
+            headers.push((
+                split.next()?.trim().to_string(),
+                split.next()?.trim().to_string(),
+            ));
+        }
+
+        let mut request = Request {
+            method,
+            path,
+            headers,
+            body: String::new(),
+        };
+        let len = request
+            .header("Content-Length")
+            .map_or(0, |len| len.parse::<usize>().unwrap());
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).await.ok()?;
+        request.body = String::from_utf8(body).unwrap();
+
+        Some(request)
+    }
+
+    // Stand-in for a WHIP endpoint at /whip/endpoint that passes on all requests it receives.
+    // The resource is created at the relative location "resource/1" with an ETag
+    async fn run_endpoint(listener: TcpListener, requests: mpsc::UnboundedSender<Request>) {
+        let mut incoming = listener.incoming();
+        while let Some(Ok(stream)) = incoming.next().await {
+            let requests = requests.clone();
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut writer = BufWriter::new(stream);
+
+                while let Some(request) = read_request(&mut reader).await {
+                    let response = match request.method.as_str() {
+                        "POST" => format!(
+                            "HTTP/1.1 201 Created\r\n\
+                             Location: resource/1\r\n\
+                             ETag: \"etag-1\"\r\n\
+                             Content-Type: application/sdp\r\n\
+                             Content-Length: {}\r\n\r\n{}",
+                            ANSWER.len(),
+                            ANSWER
+                        ),
+                        "PATCH" => "HTTP/1.1 204 No Content\r\n\r\n".to_string(),
+                        _ => "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string(),
+                    };
+
+                    let _ = requests.unbounded_send(request);
+                    writer.write_all(response.as_bytes()).await.unwrap();
+                    writer.flush().await.unwrap();
+                }
+            });
+        }
+    }
+
+    fn text(json_msg: &JsonMsg) -> WsMessage {
+        WsMessage::Text(serde_json::to_string(json_msg).unwrap())
+    }
+
+    #[test]
+    fn publish() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = format!("http://{}/whip/endpoint", listener.local_addr().unwrap());
+            let (requests_tx, mut requests) = mpsc::unbounded::<Request>();
+            task::spawn(run_endpoint(listener, requests_tx));
+
+            let args = Args::from_iter(&[
+                "webrtc-app",
+                "--signalling=whip",
+                "--server",
+                &endpoint,
+                "--token=secret",
+            ]);
+            let (app_tx, from_app) = mpsc::unbounded::<WsMessage>();
+            let (to_app, mut app_rx) = mpsc::unbounded::<WsMessage>();
+            let (quit_tx, quit_rx) = mpsc::unbounded::<()>();
+            let backend = task::spawn(run(args, from_app, to_app, quit_rx));
+
+            // A candidate gathered before the offer was sent is only trickled once the resource
+            // exists
+            app_tx
+                .unbounded_send(text(&JsonMsg::Ice {
+                    candidate: CANDIDATE.to_string(),
+                    sdp_mline_index: 0,
+                }))
+                .unwrap();
+            app_tx
+                .unbounded_send(text(&JsonMsg::Sdp {
+                    type_: "offer".to_string(),
+                    sdp: OFFER.to_string(),
+                }))
+                .unwrap();
+
+            let request = requests.next().await.unwrap();
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path, "/whip/endpoint");
+            assert_eq!(request.header("Content-Type"), Some("application/sdp"));
+            assert_eq!(request.header("Authorization"), Some("Bearer secret"));
+            assert_eq!(request.body, OFFER);
+
+            assert_eq!(
+                app_rx.next().await.unwrap(),
+                text(&JsonMsg::Sdp {
+                    type_: "answer".to_string(),
+                    sdp: ANSWER.to_string(),
+                })
+            );
+
+            // The relative location is resolved against the endpoint
+            let request = requests.next().await.unwrap();
+            assert_eq!(request.method, "PATCH");
+            assert_eq!(request.path, "/whip/resource/1");
+            assert_eq!(
+                request.header("Content-Type"),
+                Some("application/trickle-ice-sdpfrag")
+            );
+            assert_eq!(request.header("If-Match"), Some("\"etag-1\""));
+            assert_eq!(
+                request.body,
+                OfferInfo::parse(OFFER).sdp_fragment(0, CANDIDATE).unwrap()
+            );
+
+            // Later candidates are trickled right away
+            app_tx
+                .unbounded_send(text(&JsonMsg::Ice {
+                    candidate: CANDIDATE.to_string(),
+                    sdp_mline_index: 1,
+                }))
+                .unwrap();
+
+            let request = requests.next().await.unwrap();
+            assert_eq!(request.method, "PATCH");
+            assert_eq!(request.path, "/whip/resource/1");
+            assert!(request.body.contains("a=mid:audio1\r\n"));
+
+            // And on shutdown the resource is deleted again
+            quit_tx.unbounded_send(()).unwrap();
+
+            let request = requests.next().await.unwrap();
+            assert_eq!(request.method, "DELETE");
+            assert_eq!(request.path, "/whip/resource/1");
+            assert_eq!(request.header("Authorization"), Some("Bearer secret"));
+
+            assert_eq!(app_rx.next().await.unwrap(), WsMessage::Close(None));
+
+            backend.await.unwrap();
+        });
+    }
+}
@@ -7,4 +7,4 @@ if [ $# -eq 1 ]; then
   OUTDIR=$1/
 fi
 
-openssl req -x509 -newkey rsa:4096 -keyout ${OUTDIR}key.pem -out ${OUTDIR}cert.pem -days 365 -nodes -subj "/CN=example.com"
+openssl req -x509 -newkey rsa:4096 -keyout ${OUTDIR}key.pem -out ${OUTDIR}cert.pem -days 365 -nodes -subj "/CN=example.com" -addext "subjectAltName=DNS:localhost,IP:127.0.0.1"
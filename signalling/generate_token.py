@@ -0,0 +1,36 @@
+#!/usr/bin/env python3
+#
+# Generate tokens for peers of the signalling server when it is run with --auth-secret
+#
+
+import sys
+import hmac
+import json
+import time
+import base64
+import hashlib
+import argparse
+
+parser = argparse.ArgumentParser(formatter_class=argparse.ArgumentDefaultsHelpFormatter)
+parser.add_argument('--secret', required=True, help='Secret the server was started with')
+parser.add_argument('--uid', required=True, help='Uid the peer registers with')
+parser.add_argument('--room', dest='rooms', action='append', default=[],
+                    help='Room the peer may join, can be given multiple times, * allows all rooms '
+                         'and session allows 1-1 sessions')
+parser.add_argument('--expiry', default=24 * 60 * 60, type=int, help='Validity of the token (in seconds)')
+
+options = parser.parse_args(sys.argv[1:])
+
+
+def b64encode(data):
+    return base64.urlsafe_b64encode(data).rstrip(b'=').decode()
+
+
+claims = {'sub': options.uid, 'rooms': options.rooms, 'exp': int(time.time()) + options.expiry}
+
+header = b64encode(json.dumps({'alg': 'HS256', 'typ': 'JWT'}).encode())
+payload = b64encode(json.dumps(claims).encode())
+signature = hmac.new(options.secret.encode(), '{}.{}'.format(header, payload).encode(),
+                     hashlib.sha256).digest()
+
+print('{}.{}.{}'.format(header, payload, b64encode(signature)))
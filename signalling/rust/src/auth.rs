@@ -0,0 +1,164 @@
+// Verification of the tokens peers authenticate with
+//
+// Tokens are JWTs signed with HS256, as created by generate_token.py. The subject is the uid the
+// peer has to register with, and the rooms claim lists the rooms it may join. "*" allows all rooms
+// and "session" allows 1-1 sessions.
+
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use serde_json::Value;
+
+use anyhow::{anyhow, bail};
+
+// Room name that stands for 1-1 sessions in the tokens
+pub const SESSION_ROOM: &str = "session";
+
+// What a peer may join according to its token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Permissions {
+    All,
+    Rooms(HashSet<String>),
+}
+
+impl Permissions {
+    pub fn may_join(&self, room_id: &str) -> bool {
+        match self {
+            Permissions::All => true,
+            Permissions::Rooms(rooms) => rooms.contains(room_id),
+        }
+    }
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, anyhow::Error> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| anyhow!("invalid encoding: {}", err))
+}
+
+// Check the signature and expiry of a token and that it was issued for this uid, and return what
+// the peer may join
+pub fn verify_token(secret: &str, token: &str, uid: &str) -> Result<Permissions, anyhow::Error> {
+    let parts = token.split('.').collect::<Vec<_>>();
+    let (header, payload, signature) = match parts.as_slice() {
+        [header, payload, signature] => (*header, *payload, *signature),
+        _ => bail!("invalid token"),
+    };
+
+    let header_json: Value = serde_json::from_slice(&decode(header)?)?;
+    if header_json["alg"] != "HS256" {
+        bail!("unsupported algorithm {}", header_json["alg"]);
+    }
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{}.{}", header, payload).as_bytes());
+    mac.verify_slice(&decode(signature)?)
+        .map_err(|_| anyhow!("invalid signature"))?;
+
+    let claims: Value = serde_json::from_slice(&decode(payload)?)?;
+
+    if let Some(exp) = claims.get("exp") {
+        let exp = exp.as_f64().ok_or_else(|| anyhow!("invalid expiry"))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        if exp < now {
+            bail!("token expired");
+        }
+    }
+
+    // Without a uid in the token anybody holding it could impersonate any peer
+    match claims["sub"].as_str() {
+        Some(sub) if sub == uid => (),
+        Some(_) => bail!("token is not valid for uid {}", uid),
+        None => bail!("token has no uid"),
+    }
+
+    // Room ids might also be given as numbers
+    let rooms = claims["rooms"]
+        .as_array()
+        .map(|rooms| {
+            rooms
+                .iter()
+                .map(|room| match room {
+                    Value::String(room) => room.clone(),
+                    room => room.to_string(),
+                })
+                .collect::<HashSet<_>>()
+        })
+        .unwrap_or_default();
+
+    if rooms.contains("*") {
+        Ok(Permissions::All)
+    } else {
+        Ok(Permissions::Rooms(rooms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    const SECRET: &str = "secret";
+
+    fn encode(data: &[u8]) -> String {
+        base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+    }
+
+    // Same as generate_token.py
+    fn token(secret: &str, claims: Value) -> String {
+        let header = encode(
+            json!({ "alg": "HS256", "typ": "JWT" })
+                .to_string()
+                .as_bytes(),
+        );
+        let payload = encode(claims.to_string().as_bytes());
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", header, payload).as_bytes());
+        let signature = encode(&mac.finalize().into_bytes());
+
+        format!("{}.{}.{}", header, payload, signature)
+    }
+
+    #[test]
+    fn valid_token() {
+        let rooms = token(
+            SECRET,
+            json!({ "sub": "42", "rooms": ["123", 456, "session"] }),
+        );
+        let permissions = verify_token(SECRET, &rooms, "42").unwrap();
+
+        assert!(permissions.may_join("123"));
+        assert!(permissions.may_join("456"));
+        assert!(permissions.may_join(SESSION_ROOM));
+        assert!(!permissions.may_join("789"));
+
+        let all = token(SECRET, json!({ "sub": "42", "rooms": ["*"] }));
+        assert_eq!(verify_token(SECRET, &all, "42").unwrap(), Permissions::All);
+    }
+
+    #[test]
+    fn invalid_tokens() {
+        let valid = json!({ "sub": "42", "rooms": ["*"] });
+
+        // Wrong secret, other uid, no uid and expired
+        assert!(verify_token(SECRET, &token("other", valid.clone()), "42").is_err());
+        assert!(verify_token(SECRET, &token(SECRET, valid), "43").is_err());
+        assert!(verify_token(SECRET, &token(SECRET, json!({ "rooms": ["*"] })), "42").is_err());
+        assert!(verify_token(
+            SECRET,
+            &token(SECRET, json!({ "sub": "42", "rooms": ["*"], "exp": 1 })),
+            "42"
+        )
+        .is_err());
+
+        assert!(verify_token(SECRET, "not a token", "42").is_err());
+    }
+}
@@ -0,0 +1,250 @@
+// Signalling server implementing the same protocol as simple_server.py, see Protocol.md
+
+mod auth;
+mod server;
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use structopt::StructOpt;
+
+use async_std::net::TcpListener;
+use async_std::task;
+use futures::channel::mpsc;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::sink::SinkExt;
+use futures::stream::{self, StreamExt};
+
+use async_tungstenite::tungstenite;
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message as WsMessage;
+
+use anyhow::{anyhow, bail, Context};
+
+use auth::Permissions;
+use server::Server;
+
+#[derive(Debug, StructOpt)]
+struct Args {
+    #[structopt(long, default_value = "0.0.0.0")]
+    addr: String,
+    #[structopt(long, default_value = "8443")]
+    port: u16,
+    #[structopt(long, default_value = "30")]
+    keepalive_timeout: u64,
+    #[structopt(long, parse(from_os_str), default_value = ".")]
+    cert_path: PathBuf,
+    #[structopt(long)]
+    disable_ssl: bool,
+    // Also read from $WEBRTC_AUTH_SECRET
+    #[structopt(long)]
+    auth_secret: Option<String>,
+}
+
+// Create the TLS acceptor from the certificate and key created by generate_cert.sh
+fn tls_acceptor(args: &Args) -> Result<async_native_tls::TlsAcceptor, anyhow::Error> {
+    println!("Using TLS with keys in {}", args.cert_path.display());
+
+    let cert = std::fs::read(args.cert_path.join("cert.pem"))
+        .context("Certificates not found, did you run generate_cert.sh?")?;
+    let key = std::fs::read(args.cert_path.join("key.pem"))
+        .context("Certificates not found, did you run generate_cert.sh?")?;
+
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)?;
+
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
+}
+
+// Close the connection of a peer we don't accept
+async fn reject<S>(
+    ws: &mut async_tungstenite::WebSocketStream<S>,
+    code: CloseCode,
+    reason: &'static str,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let _ = ws
+        .close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }))
+        .await;
+}
+
+// Exchange the HELLO with a new peer, authenticate it and then handle its messages until either
+// side closes the connection
+#[allow(clippy::result_large_err)]
+async fn handle_connection<S>(
+    server: Arc<Mutex<Server>>,
+    args: Arc<Args>,
+    stream: S,
+) -> Result<(), anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Remember the token of the peer in case it is passed as a header
+    let mut authorization = None;
+    let mut ws = async_tungstenite::accept_hdr_async(stream, |req: &Request, resp: Response| {
+        authorization = req
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        Ok(resp)
+    })
+    .await?;
+
+    // HELLO <uid> [<token>]
+    let hello = match ws.next().await {
+        Some(Ok(WsMessage::Text(text))) => text,
+        _ => bail!("No HELLO received"),
+    };
+    let mut split = hello.splitn(3, ' ');
+    let uid = match (split.next(), split.next()) {
+        (Some("HELLO"), Some(uid)) => uid.to_string(),
+        _ => {
+            reject(&mut ws, CloseCode::Protocol, "invalid protocol").await;
+            bail!("Invalid hello {:?}", hello);
+        }
+    };
+
+    let res = server.lock().unwrap().check_uid(&uid);
+    if let Err(err) = res {
+        reject(&mut ws, CloseCode::Protocol, "invalid peer uid").await;
+        return Err(err);
+    }
+
+    // Browsers can't set headers, so the token can also be passed in the HELLO
+    let permissions = match args.auth_secret {
+        None => Permissions::All,
+        Some(ref secret) => {
+            let token = split.next().map(String::from).or_else(|| {
+                authorization
+                    .as_deref()
+                    .and_then(|auth| auth.strip_prefix("Bearer "))
+                    .map(String::from)
+            });
+
+            match token
+                .ok_or_else(|| anyhow!("no token"))
+                .and_then(|token| auth::verify_token(secret, &token, &uid))
+            {
+                Ok(permissions) => permissions,
+                Err(err) => {
+                    reject(&mut ws, CloseCode::Policy, "unauthorized").await;
+                    bail!("Failed to authenticate {:?}: {}", uid, err);
+                }
+            }
+        }
+    };
+
+    // Messages from other peers and the server for this peer
+    let (tx, mut rx) = mpsc::unbounded::<WsMessage>();
+    server.lock().unwrap().register(&uid, permissions, tx)?;
+
+    // Split the websocket into the Sink and Stream
+    let (mut ws_sink, ws_stream) = ws.split();
+    // Fuse the Stream, required for the select macro
+    let mut ws_stream = ws_stream.fuse();
+
+    // Send a regular ping to prevent bad routers from closing the connection
+    let keepalive_interval = Duration::from_secs(args.keepalive_timeout);
+    let mut keepalive = stream::unfold((), move |()| async move {
+        task::sleep(keepalive_interval).await;
+        Some(((), ()))
+    })
+    .boxed()
+    .fuse();
+
+    let res = loop {
+        let ws_msg = futures::select! {
+            ws_msg = ws_stream.select_next_some() => {
+                match ws_msg {
+                    Ok(WsMessage::Text(text)) => {
+                        server.lock().unwrap().handle_message(&uid, &text);
+                        None
+                    }
+                    Ok(WsMessage::Close(_)) => break Ok(()),
+                    Ok(_) => None,
+                    Err(err) => break Err(err.into()),
+                }
+            },
+            msg = rx.next() => {
+                match msg {
+                    // The server closes our connection, e.g. because our session ended
+                    Some(WsMessage::Close(frame)) => {
+                        let _ = ws_sink.send(WsMessage::Close(frame)).await;
+                        break Ok(());
+                    }
+                    Some(msg) => Some(msg),
+                    None => break Ok(()),
+                }
+            },
+            _ = keepalive.select_next_some() => Some(WsMessage::Ping(Vec::new())),
+            complete => break Ok(()),
+        };
+
+        if let Some(ws_msg) = ws_msg {
+            if let Err(err) = ws_sink.send(ws_msg).await {
+                break Err(err.into());
+            }
+        }
+    };
+
+    server.lock().unwrap().remove(&uid);
+
+    res
+}
+
+async fn run(args: Args) -> Result<(), anyhow::Error> {
+    let tls_acceptor = if args.disable_ssl {
+        None
+    } else {
+        Some(tls_acceptor(&args)?)
+    };
+
+    let listener = TcpListener::bind((args.addr.as_str(), args.port)).await?;
+    println!("Listening on {}:{}", args.addr, args.port);
+
+    let server = Arc::new(Mutex::new(Server::default()));
+    let args = Arc::new(args);
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = stream?;
+        let raddr = stream.peer_addr()?;
+        println!("Connected to {}", raddr);
+
+        let server = server.clone();
+        let args = args.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        task::spawn(async move {
+            let res = match tls_acceptor {
+                Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
+                    Ok(stream) => handle_connection(server, args, stream).await,
+                    Err(err) => Err(err.into()),
+                },
+                None => handle_connection(server, args, stream).await,
+            };
+
+            match res {
+                Ok(()) => println!("Disconnected from {}", raddr),
+                Err(err) => println!("Connection to {} failed: {}", raddr, err),
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let mut args = Args::from_args();
+    if args.auth_secret.is_none() {
+        args.auth_secret = std::env::var("WEBRTC_AUTH_SECRET").ok();
+    }
+
+    task::block_on(run(args))
+}
@@ -0,0 +1,349 @@
+// State of the signalling server: the registered peers and the sessions and rooms they are in
+//
+// All messages to the peers are passed to the tasks handling their connections via channels, so
+// that the state never has to be locked while waiting for a connection.
+
+use std::collections::{BTreeSet, HashMap};
+
+use futures::channel::mpsc;
+
+use async_tungstenite::tungstenite;
+use tungstenite::Message as WsMessage;
+
+use anyhow::bail;
+
+use crate::auth::{Permissions, SESSION_ROOM};
+
+// What a peer is currently doing
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Status {
+    Idle,
+    // 1-1 session with the given peer
+    Session(String),
+    Room(String),
+}
+
+#[derive(Debug)]
+struct Peer {
+    tx: mpsc::UnboundedSender<WsMessage>,
+    status: Status,
+    permissions: Permissions,
+}
+
+#[derive(Debug, Default)]
+pub struct Server {
+    peers: HashMap<String, Peer>,
+    rooms: HashMap<String, BTreeSet<String>>,
+}
+
+// Uids and room ids can be arbitrary strings without whitespace
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && !id.contains(char::is_whitespace)
+}
+
+impl Server {
+    // Check if a new peer could register with this uid
+    pub fn check_uid(&self, uid: &str) -> Result<(), anyhow::Error> {
+        if !is_valid_id(uid) {
+            bail!("Invalid uid {:?}", uid);
+        }
+
+        if self.peers.contains_key(uid) {
+            bail!("Uid {:?} already registered", uid);
+        }
+
+        Ok(())
+    }
+
+    // Register a new peer, whose messages are sent to the given channel
+    pub fn register(
+        &mut self,
+        uid: &str,
+        permissions: Permissions,
+        tx: mpsc::UnboundedSender<WsMessage>,
+    ) -> Result<(), anyhow::Error> {
+        self.check_uid(uid)?;
+
+        self.peers.insert(
+            uid.to_string(),
+            Peer {
+                tx,
+                status: Status::Idle,
+                permissions,
+            },
+        );
+        self.send(uid, "HELLO".to_string());
+
+        println!("Registered peer {:?}", uid);
+
+        Ok(())
+    }
+
+    fn send(&self, uid: &str, msg: String) {
+        if let Some(peer) = self.peers.get(uid) {
+            let _ = peer.tx.unbounded_send(WsMessage::Text(msg));
+        }
+    }
+
+    fn may_join(&self, uid: &str, room_id: &str) -> bool {
+        matches!(self.peers.get(uid), Some(peer) if peer.permissions.may_join(room_id))
+    }
+
+    // Handle a message from a registered peer
+    pub fn handle_message(&mut self, uid: &str, msg: &str) {
+        let status = match self.peers.get(uid) {
+            Some(peer) => peer.status.clone(),
+            None => return,
+        };
+
+        match status {
+            // In a session everything is passed on to the other peer
+            Status::Session(other_id) => {
+                println!("{} -> {}: {}", uid, other_id, msg);
+                self.send(&other_id, msg.to_string());
+            }
+            Status::Room(room_id) => self.handle_room_message(uid, &room_id, msg),
+            Status::Idle => {
+                let mut split = msg.splitn(2, ' ');
+                match (split.next(), split.next()) {
+                    (Some("SESSION"), Some(callee_id)) => self.start_session(uid, callee_id),
+                    (Some("ROOM"), Some(room_id)) => self.join_room(uid, room_id),
+                    _ => println!("Ignoring unknown message {:?} from {:?}", msg, uid),
+                }
+            }
+        }
+    }
+
+    fn start_session(&mut self, uid: &str, callee_id: &str) {
+        println!("{:?} command SESSION {:?}", uid, callee_id);
+
+        let callee_status = match self.peers.get(callee_id) {
+            Some(callee) => callee.status.clone(),
+            None => {
+                self.send(uid, format!("ERROR peer {:?} not found", callee_id));
+                return;
+            }
+        };
+
+        if callee_id == uid || callee_status != Status::Idle {
+            self.send(uid, format!("ERROR peer {:?} busy", callee_id));
+            return;
+        }
+
+        // Both tokens have to allow sessions
+        if !self.may_join(uid, SESSION_ROOM) || !self.may_join(callee_id, SESSION_ROOM) {
+            self.send(
+                uid,
+                format!("ERROR session with peer {:?} not allowed", callee_id),
+            );
+            return;
+        }
+
+        self.send(uid, "SESSION_OK".to_string());
+
+        println!("Session from {:?} to {:?}", uid, callee_id);
+        if let Some(peer) = self.peers.get_mut(uid) {
+            peer.status = Status::Session(callee_id.to_string());
+        }
+        if let Some(callee) = self.peers.get_mut(callee_id) {
+            callee.status = Status::Session(uid.to_string());
+        }
+    }
+
+    fn join_room(&mut self, uid: &str, room_id: &str) {
+        println!("{:?} command ROOM {:?}", uid, room_id);
+
+        // Room ids can't be the same as the name for sessions in the tokens
+        if !is_valid_id(room_id) || room_id == SESSION_ROOM {
+            self.send(uid, format!("ERROR invalid room id {:?}", room_id));
+            return;
+        }
+
+        if !self.may_join(uid, room_id) {
+            self.send(uid, format!("ERROR room {:?} not allowed", room_id));
+            return;
+        }
+
+        let room_peers = self.rooms.entry(room_id.to_string()).or_default();
+        let others = room_peers.iter().cloned().collect::<Vec<_>>();
+        room_peers.insert(uid.to_string());
+
+        self.send(uid, format!("ROOM_OK {}", others.join(" ")));
+        if let Some(peer) = self.peers.get_mut(uid) {
+            peer.status = Status::Room(room_id.to_string());
+        }
+
+        let msg = format!("ROOM_PEER_JOINED {}", uid);
+        for other_id in &others {
+            println!("room {}: {} -> {}: {}", room_id, uid, other_id, msg);
+            self.send(other_id, msg.clone());
+        }
+    }
+
+    fn handle_room_message(&mut self, uid: &str, room_id: &str, msg: &str) {
+        let room_peers = match self.rooms.get(room_id) {
+            Some(room_peers) => room_peers,
+            None => return,
+        };
+
+        if let Some(peer_msg) = msg.strip_prefix("ROOM_PEER_MSG ") {
+            let mut split = peer_msg.splitn(2, ' ');
+            let (other_id, msg) = match (split.next(), split.next()) {
+                (Some(other_id), Some(msg)) => (other_id, msg),
+                _ => {
+                    self.send(uid, "ERROR invalid ROOM_PEER_MSG".to_string());
+                    return;
+                }
+            };
+
+            if !self.peers.contains_key(other_id) {
+                self.send(uid, format!("ERROR peer {:?} not found", other_id));
+            } else if !room_peers.contains(other_id) {
+                self.send(uid, format!("ERROR peer {:?} is not in the room", other_id));
+            } else {
+                let msg = format!("ROOM_PEER_MSG {} {}", uid, msg);
+                println!("room {}: {} -> {}: {}", room_id, uid, other_id, msg);
+                self.send(other_id, msg);
+            }
+        } else if msg == "ROOM_PEER_LIST" {
+            let others = room_peers
+                .iter()
+                .filter(|pid| *pid != uid)
+                .cloned()
+                .collect::<Vec<_>>();
+            let msg = format!("ROOM_PEER_LIST {}", others.join(" "));
+            println!("room {}: -> {}: {}", room_id, uid, msg);
+            self.send(uid, msg);
+        } else {
+            self.send(uid, "ERROR invalid msg, already in room".to_string());
+        }
+    }
+
+    // Remove a peer once its connection is closed. The other peer of a session is disconnected
+    // too, to reset its state
+    pub fn remove(&mut self, uid: &str) {
+        let peer = match self.peers.remove(uid) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        match peer.status {
+            Status::Session(other_id) => {
+                if let Some(other) = self.peers.remove(&other_id) {
+                    println!("Closing connection to {}", other_id);
+                    let _ = other.tx.unbounded_send(WsMessage::Close(None));
+                }
+            }
+            Status::Room(room_id) => {
+                if let Some(room_peers) = self.rooms.get_mut(&room_id) {
+                    room_peers.remove(uid);
+
+                    let others = room_peers.iter().cloned().collect::<Vec<_>>();
+                    if others.is_empty() {
+                        self.rooms.remove(&room_id);
+                    }
+
+                    let msg = format!("ROOM_PEER_LEFT {}", uid);
+                    for other_id in &others {
+                        println!("room {}: {} -> {}: {}", room_id, uid, other_id, msg);
+                        self.send(other_id, msg.clone());
+                    }
+                }
+            }
+            Status::Idle => (),
+        }
+
+        println!("Removed peer {:?}", uid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    // Register a peer that may join the given rooms, and skip its HELLO
+    fn register(
+        server: &mut Server,
+        uid: &str,
+        rooms: Option<&[&str]>,
+    ) -> mpsc::UnboundedReceiver<WsMessage> {
+        let permissions = match rooms {
+            Some(rooms) => Permissions::Rooms(
+                rooms
+                    .iter()
+                    .map(|room| room.to_string())
+                    .collect::<HashSet<_>>(),
+            ),
+            None => Permissions::All,
+        };
+
+        let (tx, mut rx) = mpsc::unbounded();
+        server.register(uid, permissions, tx).unwrap();
+        assert_eq!(next(&mut rx), "HELLO");
+
+        rx
+    }
+
+    fn next(rx: &mut mpsc::UnboundedReceiver<WsMessage>) -> String {
+        match rx.try_recv() {
+            Ok(WsMessage::Text(text)) => text,
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn room() {
+        let mut server = Server::default();
+        let mut a = register(&mut server, "a", None);
+        let mut b = register(&mut server, "b", Some(&["123"]));
+
+        assert!(server.check_uid("a").is_err());
+        assert!(server.check_uid("with space").is_err());
+
+        server.handle_message("a", "ROOM 123");
+        assert_eq!(next(&mut a), "ROOM_OK ");
+
+        server.handle_message("b", "ROOM 456");
+        assert_eq!(next(&mut b), "ERROR room \"456\" not allowed");
+
+        server.handle_message("b", "ROOM 123");
+        assert_eq!(next(&mut b), "ROOM_OK a");
+        assert_eq!(next(&mut a), "ROOM_PEER_JOINED b");
+
+        server.handle_message("b", "ROOM_PEER_MSG a {\"sdp\": {}}");
+        assert_eq!(next(&mut a), "ROOM_PEER_MSG b {\"sdp\": {}}");
+
+        server.handle_message("a", "ROOM_PEER_LIST");
+        assert_eq!(next(&mut a), "ROOM_PEER_LIST b");
+
+        server.remove("b");
+        assert_eq!(next(&mut a), "ROOM_PEER_LEFT b");
+    }
+
+    #[test]
+    fn session() {
+        let mut server = Server::default();
+        let mut a = register(&mut server, "a", Some(&["123"]));
+        let mut b = register(&mut server, "b", Some(&[SESSION_ROOM]));
+        let mut c = register(&mut server, "c", None);
+
+        // Sessions have to be allowed for both peers
+        server.handle_message("a", "SESSION b");
+        assert_eq!(next(&mut a), "ERROR session with peer \"b\" not allowed");
+        server.handle_message("b", "SESSION a");
+        assert_eq!(next(&mut b), "ERROR session with peer \"a\" not allowed");
+
+        server.handle_message("c", "SESSION b");
+        assert_eq!(next(&mut c), "SESSION_OK");
+
+        server.handle_message("b", "{\"sdp\": {}}");
+        assert_eq!(next(&mut c), "{\"sdp\": {}}");
+
+        // Once one side leaves, the other one is disconnected
+        server.remove("c");
+        assert_eq!(b.try_recv().unwrap(), WsMessage::Close(None));
+        assert!(server.check_uid("b").is_ok());
+    }
+}
@@ -10,6 +10,11 @@
 import os
 import sys
 import ssl
+import hmac
+import json
+import time
+import base64
+import hashlib
 import logging
 import asyncio
 import websockets
@@ -34,6 +39,9 @@ class WebRTCSimpleServer(object):
         # Format: {room_id: {peer1_id, peer2_id, peer3_id, ...}}
         # Room dict with a set of peers in each room
         self.rooms = dict()
+        # Format: {uid: {room_id, ...} or None}
+        # Rooms each peer may join according to its token, None if unrestricted
+        self.peer_rooms = dict()
 
         # Event loop
         self.loop = loop
@@ -48,6 +56,7 @@ class WebRTCSimpleServer(object):
         self.cert_path = options.cert_path
         self.disable_ssl = options.disable_ssl
         self.health_path = options.health
+        self.auth_secret = options.auth_secret or os.environ.get('WEBRTC_AUTH_SECRET')
 
         # Certificate mtime, used to detect when to restart the server
         self.cert_mtime = -1
@@ -59,6 +68,61 @@ class WebRTCSimpleServer(object):
             return http.HTTPStatus.OK, [], b"OK\n"
         return None
 
+    def verify_token(self, token):
+        '''
+        Check the signature and expiry of a JWT signed with HS256, and return its claims
+        '''
+        def b64decode(data):
+            return base64.urlsafe_b64decode(data + '=' * (-len(data) % 4))
+
+        try:
+            header, payload, signature = token.split('.')
+            expected = hmac.new(self.auth_secret.encode(),
+                                '{}.{}'.format(header, payload).encode(),
+                                hashlib.sha256).digest()
+            if json.loads(b64decode(header)).get('alg') != 'HS256' or \
+               not hmac.compare_digest(b64decode(signature), expected):
+                raise ValueError('invalid signature')
+            claims = json.loads(b64decode(payload))
+        except ValueError as e:
+            raise ValueError('invalid token: {}'.format(e))
+        if 'exp' in claims and claims['exp'] < time.time():
+            raise ValueError('token expired')
+        return claims
+
+    def authenticate(self, ws, uid, token):
+        '''
+        Check the token of a peer if authentication is enabled, and return the rooms
+        it may join or None if it may join any room
+        '''
+        if not self.auth_secret:
+            return None
+        # Browsers can't set headers, so the token can also be passed in the HELLO
+        if token is None:
+            auth = ws.request_headers.get('Authorization', '')
+            if auth.startswith('Bearer '):
+                token = auth[len('Bearer '):]
+        if not token:
+            raise ValueError('no token')
+        claims = self.verify_token(token)
+        # Without a uid in the token anybody holding it could impersonate any peer
+        if 'sub' not in claims:
+            raise ValueError('token has no uid')
+        if claims['sub'] != uid:
+            raise ValueError('token is not valid for uid {!r}'.format(uid))
+        rooms = claims.get('rooms', [])
+        if '*' in rooms:
+            return None
+        return set(str(room) for room in rooms)
+
+    def may_join(self, uid, room_id):
+        '''
+        Check if the token of a peer allows joining a room, where 1-1 sessions count as
+        the room 'session'
+        '''
+        allowed_rooms = self.peer_rooms.get(uid)
+        return allowed_rooms is None or room_id in allowed_rooms
+
     async def recv_msg_ping(self, ws, raddr):
         '''
         Wait for a message forever, and send a regular ping to prevent bad routers
@@ -107,6 +171,7 @@ class WebRTCSimpleServer(object):
             if status and status != 'session':
                 await self.cleanup_room(uid, status)
             del self.peers[uid]
+            self.peer_rooms.pop(uid, None)
             await ws.close()
             print("Disconnected from peer {!r} at {!r}".format(uid, raddr))
 
@@ -170,6 +235,9 @@ class WebRTCSimpleServer(object):
                 if peer_status is not None:
                     await ws.send('ERROR peer {!r} busy'.format(callee_id))
                     continue
+                if not self.may_join(uid, 'session') or not self.may_join(callee_id, 'session'):
+                    await ws.send('ERROR session with peer {!r} not allowed'.format(callee_id))
+                    continue
                 await ws.send('SESSION_OK')
                 wsc = self.peers[callee_id][0]
                 print('Session from {!r} ({!r}) to {!r} ({!r})'
@@ -187,6 +255,9 @@ class WebRTCSimpleServer(object):
                 if room_id == 'session' or room_id.split() != [room_id]:
                     await ws.send('ERROR invalid room id {!r}'.format(room_id))
                     continue
+                if not self.may_join(uid, room_id):
+                    await ws.send('ERROR room {!r} not allowed'.format(room_id))
+                    continue
                 if room_id in self.rooms:
                     if uid in self.rooms[room_id]:
                         raise AssertionError('How did we accept a ROOM command '
@@ -215,13 +286,19 @@ class WebRTCSimpleServer(object):
         '''
         raddr = ws.remote_address
         hello = await ws.recv()
-        hello, uid = hello.split(maxsplit=1)
+        # HELLO <uid> [<token>]
+        hello, uid, *token = hello.split(maxsplit=2)
         if hello != 'HELLO':
             await ws.close(code=1002, reason='invalid protocol')
             raise Exception("Invalid hello from {!r}".format(raddr))
         if not uid or uid in self.peers or uid.split() != [uid]: # no whitespace
             await ws.close(code=1002, reason='invalid peer uid')
             raise Exception("Invalid uid {!r} from {!r}".format(uid, raddr))
+        try:
+            self.peer_rooms[uid] = self.authenticate(ws, uid, token[0] if token else None)
+        except ValueError as e:
+            await ws.close(code=1008, reason='unauthorized')
+            raise Exception("Failed to authenticate {!r} from {!r}: {}".format(uid, raddr, e))
         # Send back a HELLO
         await ws.send('HELLO')
         return uid
@@ -326,6 +403,7 @@ def main():
     parser.add_argument('--cert-path', default=os.path.dirname(__file__))
     parser.add_argument('--disable-ssl', default=False, help='Disable ssl', action='store_true')
     parser.add_argument('--health', default='/health', help='Health check route')
+    parser.add_argument('--auth-secret', default=None, help='Only accept peers with a token signed with this secret, see generate_token.py (also read from $WEBRTC_AUTH_SECRET)')
     parser.add_argument('--restart-on-cert-change', default=False, dest='cert_restart', action='store_true', help='Automatically restart if the SSL certificate changes')
 
     options = parser.parse_args(sys.argv[1:])